@@ -0,0 +1,116 @@
+use anyhow::{bail, Error};
+
+/// A pandoc style attribute block, e.g. `{#lst:main .numberLines startFrom=10 caption="Main loop"}`.
+/// `#name` sets the identifier, `.name` adds a class, and `key=value` adds a key/value pair.
+/// Values may be quoted with single or double quotes to include spaces.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Attributes {
+    pub id: Option<String>,
+    pub classes: Vec<String>,
+    pub pairs: Vec<(String, String)>,
+}
+
+impl Attributes {
+    /// Parse the text of an attribute block.
+    /// The surrounding braces are optional.
+    pub fn parse(text: &str) -> Result<Attributes, Error> {
+        let text = text.trim();
+        let text = text
+            .strip_prefix('{')
+            .and_then(|t| t.strip_suffix('}'))
+            .unwrap_or(text);
+
+        let mut attrs = Attributes::default();
+        let mut chars = text.chars().peekable();
+        loop {
+            while chars.next_if(|ch| ch.is_whitespace()).is_some() {}
+            let Some(first) = chars.next() else {
+                break;
+            };
+            match first {
+                '#' => attrs.id = Some(take_word(&mut chars)),
+                '.' => attrs.classes.push(take_word(&mut chars)),
+                _ => {
+                    let key = first.to_string() + &take_word(&mut chars);
+                    if chars.next_if_eq(&'=').is_none() {
+                        bail!(
+                            "Expected '#id', '.class' or 'key=value' in attributes, however I got '{}'.\n{}",
+                            key,
+                            text
+                        );
+                    }
+                    let value = match chars.next_if(|ch| *ch == '"' || *ch == '\'') {
+                        Some(quote) => {
+                            let mut value = String::new();
+                            loop {
+                                match chars.next() {
+                                    Some('\\') if chars.peek() == Some(&quote) => {
+                                        value.push(quote);
+                                        chars.next();
+                                    }
+                                    Some(ch) if ch == quote => break,
+                                    Some(ch) => value.push(ch),
+                                    None => bail!(
+                                        "Unterminated quote in the value of '{}'.\n{}",
+                                        key,
+                                        text
+                                    ),
+                                }
+                            }
+                            value
+                        }
+                        None => take_word(&mut chars),
+                    };
+                    attrs.pairs.push((key, value));
+                }
+            }
+        }
+        Ok(attrs)
+    }
+
+    /// The value of the first pair with one of the given keys.
+    /// Several keys are accepted since pandoc filters disagree on spelling.
+    pub fn get(&self, keys: &[&str]) -> Option<&str> {
+        self.pairs
+            .iter()
+            .find(|(k, _)| keys.contains(&k.as_str()))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Whether any of the given classes is present.
+    pub fn has_class(&self, classes: &[&str]) -> bool {
+        self.classes.iter().any(|c| classes.contains(&c.as_str()))
+    }
+}
+
+/// Take characters up to the next whitespace or `=`.
+fn take_word(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    let mut word = String::new();
+    while let Some(ch) = chars.next_if(|ch| !ch.is_whitespace() && *ch != '=') {
+        word.push(ch);
+    }
+    word
+}
+
+#[cfg(test)]
+mod attribute_tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_attributes() {
+        let attrs = Attributes::parse(
+            r#"{#lst:main .numberLines startFrom=10 hl=3-5 caption="Main \"loop\"" float=false}"#,
+        )
+        .unwrap();
+        assert_eq!(attrs.id.as_deref(), Some("lst:main"));
+        assert_eq!(attrs.classes, vec!["numberLines".to_owned()]);
+        assert_eq!(attrs.get(&["startFrom"]), Some("10"));
+        assert_eq!(attrs.get(&["hl", "highlight"]), Some("3-5"));
+        assert_eq!(attrs.get(&["caption"]), Some("Main \"loop\""));
+        assert_eq!(attrs.get(&["float"]), Some("false"));
+        assert!(attrs.has_class(&["numberLines"]));
+
+        assert!(Attributes::parse("{caption=\"unterminated}").is_err());
+        assert!(Attributes::parse("{bare}").is_err());
+    }
+}
@@ -0,0 +1,131 @@
+use crate::converter::Options;
+
+/// The kinds of GitHub style callout, `> [!NOTE]` and friends.
+/// Each becomes a tcolorbox environment of its own.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Callout {
+    Note,
+    Tip,
+    Important,
+    Warning,
+    Caution,
+}
+
+const ALL: [Callout; 5] = [
+    Callout::Note,
+    Callout::Tip,
+    Callout::Important,
+    Callout::Warning,
+    Callout::Caution,
+];
+
+impl Callout {
+    /// The callout named by a marker, ignoring case, e.g. `NOTE` in `[!NOTE]`.
+    pub fn parse(name: &str) -> Option<Callout> {
+        ALL.into_iter()
+            .find(|callout| callout.name().eq_ignore_ascii_case(name))
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Callout::Note => "note",
+            Callout::Tip => "tip",
+            Callout::Important => "important",
+            Callout::Warning => "warning",
+            Callout::Caution => "caution",
+        }
+    }
+
+    /// The environment the callout is typeset in, defined by the standalone preamble.
+    pub fn environment(&self) -> String {
+        format!("callout{}", self.name())
+    }
+
+    fn default_title(&self) -> &'static str {
+        match self {
+            Callout::Note => "Note",
+            Callout::Tip => "Tip",
+            Callout::Important => "Important",
+            Callout::Warning => "Warning",
+            Callout::Caution => "Caution",
+        }
+    }
+
+    /// An xcolor colour, which tints the box's background and frame.
+    fn default_colour(&self) -> &'static str {
+        match self {
+            Callout::Note => "blue",
+            Callout::Tip => "green",
+            Callout::Important => "violet",
+            Callout::Warning => "orange",
+            Callout::Caution => "red",
+        }
+    }
+}
+
+/// Parse a `KIND=value` command line setting, e.g. `warning=Achtung`.
+pub fn parse_setting(setting: &str) -> Result<(Callout, String), String> {
+    let (name, value) = setting
+        .split_once('=')
+        .ok_or_else(|| format!("Expected KIND=VALUE, however I got '{}'.", setting))?;
+    let callout = Callout::parse(name.trim()).ok_or_else(|| {
+        format!(
+            "Unknown callout '{}'; expected one of note, tip, important, warning or caution.",
+            name
+        )
+    })?;
+    Ok((callout, value.to_owned()))
+}
+
+/// The tcolorbox environments for every kind of callout.
+/// Each takes the box's title as an optional argument.
+pub fn preamble(opts: &Options) -> String {
+    let mut preamble = "\\usepackage{xcolor}\n\\usepackage{tcolorbox}\n".to_owned();
+    for callout in ALL {
+        let setting = |settings: &[(Callout, String)], default: &'static str| {
+            settings
+                .iter()
+                .rev()
+                .find(|(c, _)| *c == callout)
+                .map_or(default.to_owned(), |(_, value)| value.clone())
+        };
+        let title = setting(&opts.callout_titles, callout.default_title());
+        let colour = setting(&opts.callout_colours, callout.default_colour());
+        preamble.push_str(&format!(
+            "\\newtcolorbox{{{}}}[1][{}]{{colback={}!5!white, colframe={}!75!black, fonttitle=\\bfseries, title=#1}}\n",
+            callout.environment(),
+            title,
+            colour,
+            colour
+        ));
+    }
+    preamble
+}
+
+#[cfg(test)]
+mod callout_tests {
+    use super::*;
+
+    #[test]
+    fn test_callout_settings() {
+        assert_eq!(Callout::parse("WARNING"), Some(Callout::Warning));
+        assert_eq!(Callout::parse("danger"), None);
+        assert_eq!(
+            parse_setting("note=Hinweis"),
+            Ok((Callout::Note, "Hinweis".to_owned()))
+        );
+        assert!(parse_setting("danger=Gefahr").is_err());
+        assert!(parse_setting("note").is_err());
+
+        let opts = Options {
+            callout_titles: vec![(Callout::Note, "Hinweis".to_owned())],
+            callout_colours: vec![(Callout::Warning, "yellow".to_owned())],
+            ..Options::default()
+        };
+        let preamble = preamble(&opts);
+        assert!(preamble.contains("\\newtcolorbox{calloutnote}[1][Hinweis]{colback=blue!5!white"));
+        assert!(
+            preamble.contains("\\newtcolorbox{calloutwarning}[1][Warning]{colback=yellow!5!white")
+        );
+    }
+}
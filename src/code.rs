@@ -0,0 +1,799 @@
+use crate::attributes::Attributes;
+use crate::converter::Options;
+use crate::highlight::{self, HighlightTheme, Highlighter};
+use anyhow::{anyhow, bail, Context, Error};
+use clap::ValueEnum;
+use std::cell::Cell;
+use std::fs;
+use std::path::Path;
+
+/// The LaTeX package used to typeset fenced code blocks.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum CodeBackend {
+    /// `lstlisting` environments from the listings package
+    #[default]
+    Listings,
+    /// `minted` environments; requires pygments and compiling with -shell-escape
+    Minted,
+    /// Plain `Verbatim` environments from fancyvrb, without any highlighting
+    Verbatim,
+    /// fancyvrb `Verbatim` environments coloured by this tool's own highlighter,
+    /// needing only xcolor and fancyvrb.  It knows Rust, Python and C/C++;
+    /// other languages are set plain.
+    Highlight,
+}
+
+/// The features of code blocks that need packages beyond a backend's own,
+/// noted as the blocks are opened so the standalone preamble only loads those
+/// the document uses.
+#[derive(Debug, Default)]
+pub struct CodeFeatures {
+    /// Lines highlighted with `hl=`, which fancyvrb needs fvextra for
+    pub highlight_lines: Cell<bool>,
+    /// A caption or a floating block, which fancyvrb needs a `listing` float for
+    pub listing_float: Cell<bool>,
+}
+
+/// Everything the fence line says about a code block.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CodeSpec {
+    pub lang: String,
+    pub label: Option<String>,
+    /// The caption, already translated to tex
+    pub caption: Option<String>,
+    pub float: bool,
+    pub number_lines: bool,
+    pub first_line: Option<u32>,
+    /// Lines to emphasize, as a comma separated list of numbers and ranges, e.g. `3-5,8`
+    pub highlight_lines: Option<String>,
+}
+
+impl CodeSpec {
+    /// The spec for a fence with a language and nothing else.
+    pub fn new(lang: &str) -> CodeSpec {
+        CodeSpec {
+            lang: lang.to_owned(),
+            ..CodeSpec::default()
+        }
+    }
+
+    /// Build a spec from a fence's attribute block.
+    /// If no language precedes the block, the first class is taken as the language,
+    /// the way pandoc does it.
+    /// The caption is returned as written; the caller is responsible for translating it.
+    /// Blocks with a caption float unless `float=false` is given.
+    pub fn from_attributes(lang: &str, attrs: &Attributes) -> Result<CodeSpec, Error> {
+        let number_classes = ["numberLines", "numberlines", "number-lines"];
+        let lang = if lang.is_empty() {
+            attrs
+                .classes
+                .iter()
+                .find(|c| !number_classes.contains(&c.as_str()))
+                .map_or("", |c| c.as_str())
+        } else {
+            lang
+        };
+        let caption = attrs.get(&["caption"]).map(str::to_owned);
+        let float = match attrs.get(&["float"]) {
+            Some("true") => true,
+            Some("false") => false,
+            Some(other) => {
+                return Err(anyhow!(
+                    "The float attribute must be true or false, however I got '{}'.",
+                    other
+                ))
+            }
+            None => caption.is_some(),
+        };
+        let first_line = attrs
+            .get(&["startFrom", "start-from", "firstnumber"])
+            .map(|n| {
+                n.parse::<u32>().map_err(|_| {
+                    anyhow!(
+                        "The first line number must be a number, however I got '{}'.",
+                        n
+                    )
+                })
+            })
+            .transpose()?;
+        // As in pandoc, only `.numberLines` numbers the lines; `startFrom` just says
+        // where numbering starts
+        let number_lines = attrs.has_class(&number_classes);
+        Ok(CodeSpec {
+            lang: lang.to_owned(),
+            label: attrs.id.clone(),
+            caption,
+            float,
+            number_lines,
+            first_line: first_line.filter(|_| number_lines),
+            highlight_lines: attrs.get(&["hl", "highlight"]).map(str::to_owned),
+        })
+    }
+}
+
+/// The part of a source file to include in a listing.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Region {
+    Whole,
+    /// A one-based, inclusive range of lines; either end may be left open
+    Lines(Option<usize>, Option<usize>),
+    /// The lines between `ANCHOR: name` and `ANCHOR_END: name` markers
+    Anchor(String),
+}
+
+impl Region {
+    /// Parse a line range such as `10-42`, `10-`, `-42` or `10`.
+    /// The GitHub permalink form `L10-L42` is accepted too.
+    pub fn parse_lines(text: &str) -> Result<Region, Error> {
+        let number = |n: &str| -> Result<Option<usize>, Error> {
+            let n = n.trim().trim_start_matches('L');
+            if n.is_empty() {
+                Ok(None)
+            } else {
+                n.parse::<usize>()
+                    .map(Some)
+                    .map_err(|_| anyhow!("Unable to read the line range '{}'.", text))
+            }
+        };
+        match text.split_once('-') {
+            Some((start, end)) => Ok(Region::Lines(number(start)?, number(end)?)),
+            None => {
+                let line = number(text)?;
+                Ok(Region::Lines(line, line))
+            }
+        }
+    }
+}
+
+/// Read the lines of a source file to include in a listing.
+/// The path is relative to the directory of the markdown file.
+/// Lines holding anchor markers are dropped, and the common indentation is removed.
+pub fn read_included_code(
+    base_dir: &Path,
+    path: &str,
+    region: &Region,
+) -> Result<Vec<String>, Error> {
+    let full_path = base_dir.join(path);
+    let contents = fs::read_to_string(&full_path)
+        .with_context(|| format!("Unable to read the included file {}", full_path.display()))?;
+    let all_lines = contents.lines().collect::<Vec<_>>();
+
+    let selected = match region {
+        Region::Whole => all_lines,
+        Region::Lines(start, end) => {
+            let start = start.unwrap_or(1);
+            let end = end.unwrap_or(all_lines.len());
+            if start < 1 || start > end || end > all_lines.len() {
+                bail!(
+                    "The line range {}-{} is outside of {}, which has {} lines.",
+                    start,
+                    end,
+                    path,
+                    all_lines.len()
+                );
+            }
+            all_lines[start - 1..end].to_vec()
+        }
+        Region::Anchor(name) => {
+            let start_marker = format!("ANCHOR: {}", name);
+            let end_marker = format!("ANCHOR_END: {}", name);
+            let start = all_lines
+                .iter()
+                .position(|l| l.trim_end().ends_with(&start_marker))
+                .ok_or_else(|| anyhow!("No '{}' marker in {}.", start_marker, path))?;
+            let end = all_lines[start..]
+                .iter()
+                .position(|l| l.trim_end().ends_with(&end_marker))
+                .map_or(all_lines.len(), |pos| start + pos);
+            all_lines[start + 1..end].to_vec()
+        }
+    };
+
+    let kept = selected
+        .into_iter()
+        .filter(|l| !l.contains("ANCHOR: ") && !l.contains("ANCHOR_END: "))
+        .collect::<Vec<_>>();
+    // The common indent is counted in characters, as some whitespace takes several bytes
+    let indent = kept
+        .iter()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| l.chars().take_while(|ch| ch.is_whitespace()).count())
+        .min()
+        .unwrap_or(0);
+    Ok(kept
+        .iter()
+        .map(|l| {
+            let start = l
+                .char_indices()
+                .take(indent)
+                .take_while(|(_, ch)| ch.is_whitespace())
+                .last()
+                .map_or(0, |(i, ch)| i + ch.len_utf8());
+            l[start..].to_owned()
+        })
+        .collect())
+}
+
+/// Guess the fence language of an included file from its extension.
+/// Unrecognized extensions give no language at all, rather than one the backends won't know.
+pub fn language_for_path(path: &str) -> &'static str {
+    let ext = Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("");
+    match ext {
+        "rs" => "rust",
+        "py" => "python",
+        "c" | "h" => "c",
+        "cpp" | "cc" | "cxx" | "hpp" => "cpp",
+        "java" => "java",
+        "js" => "javascript",
+        "ts" => "typescript",
+        "go" => "go",
+        "sh" | "bash" => "bash",
+        "sql" => "sql",
+        "json" => "json",
+        "toml" => "toml",
+        "yaml" | "yml" => "yaml",
+        "rb" => "ruby",
+        "hs" => "haskell",
+        "tex" => "tex",
+        _ => "",
+    }
+}
+
+/// The fence that opened a code block: a run of backticks or tildes,
+/// indented by up to three spaces.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fence {
+    pub ch: char,
+    pub len: usize,
+    pub indent: usize,
+}
+
+impl Fence {
+    /// A closing fence uses the same character, is at least as long as the opening one,
+    /// is indented by no more than three spaces, and has nothing after it but spaces.
+    pub fn is_closed_by(&self, line: &str) -> bool {
+        let indent = line.len() - line.trim_start_matches(' ').len();
+        let rest = line[indent..].trim_end();
+        indent <= 3 && rest.len() >= self.len && rest.chars().all(|ch| ch == self.ch)
+    }
+}
+
+/// An open code block.
+/// Holds the tex that closes the block and, for the highlight backend,
+/// the highlighter's state between lines.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CodeBlock {
+    end: String,
+    highlighter: Option<(Highlighter, HighlightTheme)>,
+    /// The code came from a file, so anything between the fences is ignored
+    pub included: bool,
+    /// The fence that opened the block, or None for an indented block
+    pub fence: Option<Fence>,
+    /// Blank lines seen in an indented block that aren't yet known to be inside it
+    pub pending_blank_lines: usize,
+    /// The block couldn't be opened, so its lines are dropped
+    pub skipped: bool,
+    /// The block is in the argument of a command, e.g. a footnote, where verbatim
+    /// environments break, so its lines are set as escaped `\texttt` instead
+    in_argument: bool,
+    /// The lines written so far, as lines in an argument are joined with `\\`
+    lines: usize,
+}
+
+impl CodeBlock {
+    /// A block that writes nothing, for a block that couldn't be opened.
+    /// Its lines are still skipped, up to the closing fence of a fenced block,
+    /// which would otherwise open a block of its own.
+    pub fn skipped() -> CodeBlock {
+        CodeBlock {
+            end: String::new(),
+            highlighter: None,
+            included: false,
+            fence: None,
+            pending_blank_lines: 0,
+            skipped: true,
+            in_argument: false,
+            lines: 0,
+        }
+    }
+
+    /// Format one line of code.
+    pub fn line(&mut self, line: &str) -> String {
+        if self.in_argument {
+            let text = match &mut self.highlighter {
+                Some((h, theme)) => h.text_line(line, *theme),
+                None => highlight::escape_text(line),
+            };
+            let sep = if self.lines == 0 { "" } else { "\\\\" };
+            self.lines += 1;
+            // Spaces in code don't collapse, least of all those that indent it
+            return format!("{}\\texttt{{{}}}\n", sep, text.replace(' ', "~"));
+        }
+        match &mut self.highlighter {
+            Some((h, theme)) => format!("{}\n", h.verbatim_line(line, *theme)),
+            None => format!("{}\n", line),
+        }
+    }
+
+    /// The tex that closes the block.
+    pub fn end(&self) -> &str {
+        &self.end
+    }
+}
+
+/// Open a code block with the backend selected in the options.
+/// Returns the tex that opens the block along with the block itself,
+/// which formats each line of code and supplies the closing tex.
+pub fn open_code_block(opts: &Options, spec: &CodeSpec) -> (String, CodeBlock) {
+    let (begin, end) = if opts.in_argument {
+        if spec.float || spec.caption.is_some() || spec.label.is_some() {
+            eprintln!(
+                "A code block in a footnote can't have a caption or label; ignoring them for a {} block.",
+                spec.lang
+            );
+        }
+        ("\\par\\noindent\n".to_owned(), "\\par\n".to_owned())
+    } else {
+        if spec.highlight_lines.is_some() {
+            opts.code_features.highlight_lines.set(true);
+        }
+        if spec.float || spec.caption.is_some() {
+            opts.code_features.listing_float.set(true);
+        }
+        opts.code_backend.code_block(spec)
+    };
+    let highlighter = match opts.code_backend {
+        CodeBackend::Highlight if highlight::is_supported(&spec.lang) => {
+            Some((Highlighter::new(&spec.lang), opts.highlight_theme))
+        }
+        _ => None,
+    };
+    (
+        begin,
+        CodeBlock {
+            end,
+            highlighter,
+            included: false,
+            fence: None,
+            pending_blank_lines: 0,
+            skipped: false,
+            in_argument: opts.in_argument,
+            lines: 0,
+        },
+    )
+}
+
+/// Characters tried, in order, as the delimiter of `\verb` and friends.
+const VERB_DELIMITERS: &[char] = &['|', '!', '+', '=', '/', ':', ';', '@', '"', '\'', '-'];
+
+/// Render a code span in running text with the backend selected in the options.
+/// In fragile places, i.e., command arguments such as captions, footnotes and table cells,
+/// the verbatim commands would break, so the code is escaped and set in `\texttt` instead.
+pub fn inline_code(opts: &Options, code: &str, lang: Option<&str>, fragile: bool) -> String {
+    let delimiter = VERB_DELIMITERS.iter().find(|d| !code.contains(**d));
+    match (opts.code_backend, delimiter) {
+        (CodeBackend::Highlight, _) | (_, None) => highlighted_texttt(opts, code, lang),
+        _ if fragile => highlighted_texttt(opts, code, lang),
+        (CodeBackend::Listings, Some(d)) => match lang {
+            Some(lang) => format!("\\lstinline[{}]{}{}{}", listings_option(lang), d, code, d),
+            None => format!("\\lstinline{}{}{}", d, code, d),
+        },
+        (CodeBackend::Minted, Some(d)) => format!(
+            "\\mintinline{{{}}}{}{}{}",
+            minted_language(lang.unwrap_or("")),
+            d,
+            code,
+            d
+        ),
+        (CodeBackend::Verbatim, Some(d)) => format!("\\verb{}{}{}", d, code, d),
+    }
+}
+
+/// Escaped `\texttt`, coloured by the built-in highlighter when the backend is highlight.
+fn highlighted_texttt(opts: &Options, code: &str, lang: Option<&str>) -> String {
+    match lang {
+        Some(lang)
+            if opts.code_backend == CodeBackend::Highlight && highlight::is_supported(lang) =>
+        {
+            format!(
+                "\\texttt{{{}}}",
+                Highlighter::new(lang).text_line(code, opts.highlight_theme)
+            )
+        }
+        _ => format!("\\texttt{{{}}}", highlight::escape_text(code)),
+    }
+}
+
+impl CodeBackend {
+    /// Produce the tex that opens and closes a code block.
+    /// The first element of the tuple is written when the fence opens,
+    /// the second when it closes; the code itself goes between them.
+    fn code_block(&self, spec: &CodeSpec) -> (String, String) {
+        match self {
+            CodeBackend::Listings => {
+                let mut options = Vec::new();
+                if !spec.lang.is_empty() {
+                    options.push(listings_option(&spec.lang));
+                }
+                if spec.number_lines {
+                    options.push("numbers=left".to_owned());
+                }
+                if let Some(first) = spec.first_line {
+                    options.push(format!("firstnumber={}", first));
+                }
+                if spec.highlight_lines.is_some() {
+                    eprintln!(
+                        "The listings code backend cannot highlight lines; ignoring hl for a {} block.",
+                        spec.lang
+                    );
+                }
+                if let Some(label) = &spec.label {
+                    options.push(format!("label={}", label));
+                }
+                if let Some(caption) = &spec.caption {
+                    options.push(format!("caption={{{}}}", caption));
+                }
+                if spec.float {
+                    options.push("float".to_owned());
+                }
+                let mut begin = "\\begin{lstlisting}".to_owned();
+                if spec.label.is_some() || spec.caption.is_some() {
+                    begin.push_str(&format!("[\n\t{}]", options.join(",\n\t")));
+                } else if !options.is_empty() {
+                    begin.push_str(&format!("[{}]", options.join(",")));
+                }
+                begin.push('\n');
+                (begin, "\\end{lstlisting}\n".to_owned())
+            }
+            CodeBackend::Minted => {
+                let options = fancyvrb_options(spec, "linenos");
+                let begin = format!(
+                    "\\begin{{minted}}{}{{{}}}\n",
+                    bracketed(&options),
+                    minted_language(&spec.lang)
+                );
+                let end = "\\end{minted}\n".to_owned();
+                wrap_in_listing(begin, end, spec)
+            }
+            CodeBackend::Verbatim => {
+                let options = fancyvrb_options(spec, "numbers=left");
+                let begin = format!("\\begin{{Verbatim}}{}\n", bracketed(&options));
+                let end = "\\end{Verbatim}\n".to_owned();
+                wrap_in_listing(begin, end, spec)
+            }
+            CodeBackend::Highlight => {
+                let mut options = Vec::new();
+                if highlight::is_supported(&spec.lang) {
+                    options.push("commandchars=\\\\\\{\\}".to_owned());
+                }
+                options.extend(fancyvrb_options(spec, "numbers=left"));
+                let begin = format!("\\begin{{Verbatim}}{}\n", bracketed(&options));
+                let end = "\\end{Verbatim}\n".to_owned();
+                wrap_in_listing(begin, end, spec)
+            }
+        }
+    }
+
+    /// The preamble lines needed by this backend in a standalone document,
+    /// given the features of the document's code blocks.
+    pub fn preamble(&self, features: &CodeFeatures) -> String {
+        let mut preamble = match self {
+            CodeBackend::Listings => {
+                return concat!(
+                "\\usepackage{listings}\n",
+                "\\lstdefinelanguage{Rust}{\n",
+                "\tmorekeywords={as,async,await,break,const,continue,crate,dyn,else,enum,extern,\n",
+                "\t\tfn,for,if,impl,in,let,loop,match,mod,move,mut,pub,ref,return,self,Self,\n",
+                "\t\tstatic,struct,super,trait,type,unsafe,use,where,while},\n",
+                "\tsensitive=true,\n",
+                "\tmorecomment=[l]{//},\n",
+                "\tmorecomment=[s]{/*}{*/},\n",
+                "\tmorestring=[b]\",\n",
+                "}\n",
+                "\\lstdefinelanguage{Go}{\n",
+                "\tmorekeywords={break,case,chan,const,continue,default,defer,else,fallthrough,\n",
+                "\t\tfor,func,go,goto,if,import,interface,map,package,range,return,select,\n",
+                "\t\tstruct,switch,type,var},\n",
+                "\tsensitive=true,\n",
+                "\tmorecomment=[l]{//},\n",
+                "\tmorecomment=[s]{/*}{*/},\n",
+                "\tmorestring=[b]\",\n",
+                "\tmorestring=[b]`,\n",
+                "}\n",
+            )
+                .to_owned()
+            }
+            CodeBackend::Minted => {
+                return concat!(
+                "% minted runs pygments, so this document must be compiled with -shell-escape,\n",
+                "% e.g., xelatex -shell-escape paper.tex\n",
+                "\\usepackage{minted}\n",
+                "\\usepackage{caption}\n",
+            )
+                .to_owned()
+            }
+            CodeBackend::Verbatim => "\\usepackage{fancyvrb}\n".to_owned(),
+            CodeBackend::Highlight => "\\usepackage{xcolor}\n\\usepackage{fancyvrb}\n".to_owned(),
+        };
+        // Plain fancyvrb is all the Verbatim environments need, unless the document
+        // highlights lines or gives a block a caption
+        if features.highlight_lines.get() {
+            preamble.push_str("\\usepackage{fvextra}\n");
+        }
+        if features.listing_float.get() {
+            preamble.push_str(concat!(
+                "\\usepackage{float}\n",
+                "\\newfloat{listing}{htbp}{lol}\n",
+                "\\floatname{listing}{Listing}\n",
+                "\\usepackage{caption}\n",
+            ));
+        }
+        preamble
+    }
+}
+
+/// Options shared by minted and fancyvrb (with fvextra for highlighted lines),
+/// which only differ in how line numbering is switched on.
+fn fancyvrb_options(spec: &CodeSpec, numbering: &str) -> Vec<String> {
+    let mut options = Vec::new();
+    if spec.number_lines {
+        options.push(numbering.to_owned());
+    }
+    if let Some(first) = spec.first_line {
+        options.push(format!("firstnumber={}", first));
+    }
+    if let Some(lines) = &spec.highlight_lines {
+        options.push(format!("highlightlines={{{}}}", lines));
+    }
+    options
+}
+
+fn bracketed(options: &[String]) -> String {
+    if options.is_empty() {
+        String::new()
+    } else {
+        format!("[{}]", options.join(","))
+    }
+}
+
+/// Minted and fancyvrb blocks can't float on their own,
+/// so floating blocks are placed in a `listing` environment with the caption and label.
+/// Captions on blocks that don't float are set with `\captionof` from the caption package.
+fn wrap_in_listing(begin: String, end: String, spec: &CodeSpec) -> (String, String) {
+    let label = spec
+        .label
+        .as_ref()
+        .map_or(String::new(), |l| format!("\\label{{{}}}", l));
+    match (&spec.caption, spec.float) {
+        (Some(caption), true) => (
+            format!("\\begin{{listing}}[htbp]\n{}", begin),
+            format!(
+                "{}\\caption{{{}}}{}\n\\end{{listing}}\n",
+                end, caption, label
+            ),
+        ),
+        (None, true) => (
+            format!("\\begin{{listing}}[htbp]\n{}", begin),
+            format!("{}{}\\end{{listing}}\n", end, label),
+        ),
+        (Some(caption), false) => (
+            begin,
+            format!("{}\\captionof{{listing}}{{{}}}{}\n", end, caption, label),
+        ),
+        (None, false) => (begin, end),
+    }
+}
+
+/// Map a fence language to the name of the corresponding listings language.
+/// Rust and Go aren't among the languages listings defines, so the standalone
+/// preamble defines them.  Other languages it doesn't know have no name here.
+fn listings_language(lang: &str) -> Option<&'static str> {
+    let name = match lang.to_lowercase().as_str() {
+        "bash" | "sh" | "shell" | "zsh" => "bash",
+        "c" => "C",
+        "cpp" | "c++" | "cxx" | "hpp" => "C++",
+        "csharp" | "c#" | "cs" => "[Sharp]C",
+        "go" | "golang" => "Go",
+        "haskell" | "hs" => "Haskell",
+        "html" => "HTML",
+        "java" => "Java",
+        "latex" | "tex" => "[LaTeX]TeX",
+        "lisp" => "Lisp",
+        "matlab" => "Matlab",
+        "perl" | "pl" => "Perl",
+        "python" | "py" => "Python",
+        "ruby" | "rb" => "Ruby",
+        "rust" | "rs" => "Rust",
+        "sql" => "SQL",
+        "xml" => "XML",
+        _ => return None,
+    };
+    Some(name)
+}
+
+/// The listings option selecting a fence language.
+/// A language listings doesn't know is taken for a style of the same name,
+/// which the document is expected to define with `\lstdefinestyle`.
+fn listings_option(lang: &str) -> String {
+    match listings_language(lang) {
+        Some(name) => format!("language={}", name),
+        None => format!("style={}", lang),
+    }
+}
+
+/// Map a fence language to the name of the corresponding pygments lexer.
+/// Pygments already knows most languages by their common names, so only aliases
+/// it doesn't understand are translated.  An empty language becomes plain text.
+fn minted_language(lang: &str) -> String {
+    match lang.to_lowercase().as_str() {
+        "" => "text".to_owned(),
+        "rs" => "rust".to_owned(),
+        "py" => "python".to_owned(),
+        "sh" | "shell" => "bash".to_owned(),
+        "c++" => "cpp".to_owned(),
+        "c#" => "csharp".to_owned(),
+        other => other.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod code_tests {
+    use super::*;
+
+    fn floating(lang: &str, label: &str, caption: &str) -> CodeSpec {
+        CodeSpec {
+            label: Some(label.to_owned()),
+            caption: Some(caption.to_owned()),
+            float: true,
+            ..CodeSpec::new(lang)
+        }
+    }
+
+    #[test]
+    fn test_code_block_backends() {
+        let (begin, end) = CodeBackend::Listings.code_block(&CodeSpec::new("py"));
+        assert_eq!(begin, "\\begin{lstlisting}[language=Python]\n");
+        assert_eq!(end, "\\end{lstlisting}\n");
+        // Rust is defined in the preamble, and unknown languages are taken for styles
+        let (begin, _) = CodeBackend::Listings.code_block(&CodeSpec::new("rust"));
+        assert_eq!(begin, "\\begin{lstlisting}[language=Rust]\n");
+        let (begin, _) = CodeBackend::Listings.code_block(&CodeSpec::new("zig"));
+        assert_eq!(begin, "\\begin{lstlisting}[style=zig]\n");
+        assert!(CodeBackend::Listings
+            .preamble(&CodeFeatures::default())
+            .contains("\\lstdefinelanguage{Rust}"));
+
+        let (begin, end) = CodeBackend::Minted.code_block(&CodeSpec::new("rs"));
+        assert_eq!(begin, "\\begin{minted}{rust}\n");
+        assert_eq!(end, "\\end{minted}\n");
+
+        let (begin, end) =
+            CodeBackend::Minted.code_block(&floating("python", "lst:a", "A caption"));
+        assert_eq!(begin, "\\begin{listing}[htbp]\n\\begin{minted}{python}\n");
+        assert_eq!(
+            end,
+            "\\end{minted}\n\\caption{A caption}\\label{lst:a}\n\\end{listing}\n"
+        );
+
+        let (begin, _) = CodeBackend::Verbatim.code_block(&CodeSpec::new("python"));
+        assert_eq!(begin, "\\begin{Verbatim}\n");
+
+        let opts = Options {
+            code_backend: CodeBackend::Highlight,
+            ..Options::default()
+        };
+        let (begin, mut block) = open_code_block(&opts, &CodeSpec::new("python"));
+        assert_eq!(begin, "\\begin{Verbatim}[commandchars=\\\\\\{\\}]\n");
+        assert_eq!(
+            block.line("pass"),
+            "\\textcolor[HTML]{0033B3}{\\textbf{pass}}\n"
+        );
+        assert_eq!(block.end(), "\\end{Verbatim}\n");
+        // Plain fancyvrb does, until a block highlights lines or has a caption
+        assert_eq!(
+            opts.code_backend.preamble(&opts.code_features),
+            "\\usepackage{xcolor}\n\\usepackage{fancyvrb}\n"
+        );
+        open_code_block(&opts, &floating("python", "lst:a", "A caption"));
+        let preamble = opts.code_backend.preamble(&opts.code_features);
+        assert!(preamble.contains("\\newfloat{listing}") && !preamble.contains("fvextra"));
+    }
+
+    #[test]
+    fn test_inline_code() {
+        let mut opts = Options::default();
+        assert_eq!(inline_code(&opts, "a|b", None, false), "\\lstinline!a|b!");
+        assert_eq!(
+            inline_code(&opts, "x_y", Some("py"), false),
+            "\\lstinline[language=Python]|x_y|"
+        );
+        assert_eq!(
+            inline_code(&opts, "x_y", Some("rs"), false),
+            "\\lstinline[language=Rust]|x_y|"
+        );
+        assert_eq!(
+            inline_code(&opts, "a_b%c{}", None, true),
+            "\\texttt{a\\_b\\%c\\{\\}}"
+        );
+
+        opts.code_backend = CodeBackend::Minted;
+        assert_eq!(
+            inline_code(&opts, "let x", Some("rust"), false),
+            "\\mintinline{rust}|let x|"
+        );
+
+        opts.code_backend = CodeBackend::Verbatim;
+        assert_eq!(inline_code(&opts, "~/a\\b", None, false), "\\verb|~/a\\b|");
+
+        opts.code_backend = CodeBackend::Highlight;
+        opts.highlight_theme = HighlightTheme::Monochrome;
+        assert_eq!(
+            inline_code(&opts, "fn _()", Some("rust"), false),
+            "\\texttt{\\textbf{fn} \\_()}"
+        );
+    }
+
+    #[test]
+    fn test_included_code() {
+        let dir = std::env::temp_dir().join("md_to_tex_test_included_code");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("demo.rs"),
+            "fn main() {\n    // ANCHOR: body\n    let x = 1;\n\n    println!(\"{}\", x);\n    // ANCHOR_END: body\n}\n",
+        )
+        .unwrap();
+
+        let lines =
+            read_included_code(&dir, "demo.rs", &Region::Anchor("body".to_owned())).unwrap();
+        assert_eq!(lines, vec!["let x = 1;", "", "println!(\"{}\", x);"]);
+
+        let lines =
+            read_included_code(&dir, "demo.rs", &Region::parse_lines("L1-L3").unwrap()).unwrap();
+        assert_eq!(lines, vec!["fn main() {", "    let x = 1;"]);
+
+        assert!(
+            read_included_code(&dir, "demo.rs", &Region::parse_lines("5-50").unwrap()).is_err()
+        );
+        fs::write(dir.join("wide.txt"), "\u{3000}\u{3000}wide\n  narrow\n").unwrap();
+        let lines = read_included_code(&dir, "wide.txt", &Region::Whole).unwrap();
+        assert_eq!(lines, vec!["wide", "narrow"]);
+        assert!(read_included_code(&dir, "missing.rs", &Region::Whole).is_err());
+        assert_eq!(language_for_path("examples/demo.rs"), "rust");
+    }
+
+    #[test]
+    fn test_code_attributes() {
+        let attrs = Attributes::parse(
+            r#"{#lst:main .numberLines startFrom=10 hl=3-5 caption="Main loop" float=false}"#,
+        )
+        .unwrap();
+        let spec = CodeSpec::from_attributes("rust", &attrs).unwrap();
+
+        let (begin, end) = CodeBackend::Minted.code_block(&spec);
+        assert_eq!(
+            begin,
+            "\\begin{minted}[linenos,firstnumber=10,highlightlines={3-5}]{rust}\n"
+        );
+        assert_eq!(
+            end,
+            "\\end{minted}\n\\captionof{listing}{Main loop}\\label{lst:main}\n"
+        );
+
+        let (begin, end) = CodeBackend::Listings.code_block(&spec);
+        assert_eq!(
+            begin,
+            "\\begin{lstlisting}[\n\tlanguage=Rust,\n\tnumbers=left,\n\tfirstnumber=10,\n\tlabel=lst:main,\n\tcaption={Main loop}]\n"
+        );
+        assert_eq!(end, "\\end{lstlisting}\n");
+
+        let attrs = Attributes::parse("{startFrom=5}").unwrap();
+        let spec = CodeSpec::from_attributes("rust", &attrs).unwrap();
+        assert!(!spec.number_lines && spec.first_line.is_none());
+
+        let attrs = Attributes::parse("{.python caption=Loop float=maybe}").unwrap();
+        assert!(CodeSpec::from_attributes("", &attrs).is_err());
+    }
+}
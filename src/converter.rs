@@ -1,9 +1,24 @@
+use crate::attributes::Attributes;
+use crate::callout::{self, Callout};
+use crate::code::{
+    inline_code, language_for_path, open_code_block, read_included_code, CodeBackend, CodeBlock,
+    CodeFeatures, CodeSpec, Fence, Region,
+};
+use crate::emphasis::emphasis;
+use crate::footnotes::{FootnoteMode, Footnotes};
+use crate::highlight::HighlightTheme;
+use crate::macros::MathMacros;
+use crate::math::MathBlock;
+use crate::theorem::{self, TheoremCounters, TheoremNumbering};
 use anyhow::{anyhow, bail, Error};
-use itertools::Itertools;
+use clap::ValueEnum;
+use itertools::{Either, Itertools};
 use lazy_static::lazy_static;
 use regex::{Captures, Regex};
 use smallvec::{smallvec, SmallVec};
-use std::io::{self, BufRead};
+use std::io;
+use std::path::PathBuf;
+use std::rc::Rc;
 
 // Constant values; must be loaded lazily because they can panic (only if the regex is bad)
 lazy_static! {
@@ -18,23 +33,71 @@ lazy_static! {
     static ref RE_SUBSUBSECTION_HEADER: Regex =
         Regex::new(r#"^##### (\[]\{#(?<label>.+)\})?(?<head>.*)$"#).unwrap();
     static ref RE_TABLE_HEADER: Regex = Regex::new(r#"(<!--(?<desc>.+)-->)?(?<label>.*)"#).unwrap();
-    static ref RE_START_ENUMERATE: Regex = Regex::new(r#"^[0-9]+\. (?<item>.+)$"#).unwrap();
+    static ref RE_LINE_BREAK_TAG: Regex = Regex::new(r#"(?i)<br\s*/?>"#).unwrap();
+    static ref RE_START_ENUMERATE: Regex = Regex::new(
+        r#"^(?<marker>[0-9]{1,9}|[a-zA-Z]|[ivxlcdm]+|[IVXLCDM]+)(?<delim>[.)]) (?<item>.+)$"#
+    )
+    .unwrap();
     static ref RE_START_ITEMIZE: Regex = Regex::new(r#"^[*+-] (?<item>.+)$"#).unwrap();
+    static ref RE_TASK_ITEM: Regex = Regex::new(r#"^\[(?<mark>[ xX])]\s+(?<text>.*)$"#).unwrap();
+    static ref RE_DEFINITION: Regex = Regex::new(r#"^[:~]\s+(?<def>.+)$"#).unwrap();
+    static ref RE_ATTRIBUTION: Regex =
+        Regex::new(r#"^(-{2,3}|\x{2014}|\x{2015})\s*(?<who>.+)$"#).unwrap();
+    static ref RE_CALLOUT: Regex =
+        Regex::new(r#"^\[!(?<kind>[A-Za-z]+)]\s*(?<title>.*)$"#).unwrap();
+    static ref RE_DIV_OPEN: Regex =
+        Regex::new(r#"^ {0,3}(?<fence>:{3,})\s*(?<attrs>\{.*\}|[^\s{}:]+)\s*:*\s*$"#).unwrap();
+    static ref RE_DIV_CLOSE: Regex = Regex::new(r#"^ {0,3}(?<fence>:{3,})\s*$"#).unwrap();
     static ref RE_LINK: Regex = Regex::new(r#"\[(?<text>.*)]\((?<link>.+)\)"#).unwrap();
     static ref RE_SUPERSCRIPT: Regex = Regex::new(r#"\^(?<super>.+?)\^"#).unwrap();
     static ref RE_BOLD_FONT: Regex = Regex::new(r#"\*(?<bold>.+?)\*"#).unwrap();
-    static ref RE_MONO_FONT: Regex = Regex::new(r#"`(?<mono>.+?)`"#).unwrap();
+    static ref RE_CODE_SPAN_LANG: Regex = Regex::new(r#"^\{\.(?<lang>[^\s}]+)\}"#).unwrap();
+    static ref RE_PLACEHOLDER: Regex = Regex::new("\u{E000}(?<idx>[0-9]+)\u{E001}").unwrap();
+    static ref RE_MARK_PLACEHOLDER: Regex = Regex::new("\u{E004}(?<idx>[0-9]+)\u{E005}").unwrap();
+    static ref RE_NOTE_PLACEHOLDER: Regex = Regex::new("\u{E002}(?<idx>[0-9]+)\u{E003}").unwrap();
+    static ref RE_MATH_PLACEHOLDER: Regex = Regex::new("\u{E008}(?<idx>[0-9]+)\u{E009}").unwrap();
+    static ref RE_LINK_PLACEHOLDER: Regex = Regex::new("\u{E006}(?<idx>[0-9]+)\u{E007}").unwrap();
     static ref RE_SINGLE_QUOTE: Regex = Regex::new(r#"'(?<quote>.+?)'"#).unwrap();
     static ref RE_DOUBLE_QUOTE: Regex = Regex::new(r#""(?<quote>.+?)""#).unwrap();
     static ref RE_EMPH_FONT: Regex = Regex::new(r#"_(?<emph>.+?[^\\])_"#).unwrap();
+    static ref RE_STRIKEOUT: Regex =
+        Regex::new(r#"~~(?<strike>[^~\s](?:[^~]*[^~\s])?)~~"#).unwrap();
+    static ref RE_SUBSCRIPT: Regex = Regex::new(r#"~(?<sub>[^~\s]+)~"#).unwrap();
     static ref RE_FOOTNOTE_REF: Regex = Regex::new(r#"\[\^(?<mark>.+?)]"#).unwrap();
-    static ref RE_FOOTNOTE_BODY: Regex = Regex::new(r#"^\[\^(?<mark>.+?)](?<body>.+?)$"#).unwrap();
+    static ref RE_FOOTNOTE_BODY: Regex =
+        Regex::new(r#"^\[\^(?<mark>.+?)]:?\s*(?<body>.+?)$"#).unwrap();
     static ref RE_COMMENT: Regex = Regex::new(r#"<!--(.*)-->"#).unwrap();
+    static ref RE_INLINE_MATH: Regex =
+        Regex::new(r#"\$(?<math>[^\s$](?:[^$]*[^\s$])?)\$"#).unwrap();
     static ref RE_LINE_COMMENT: Regex = Regex::new(r#"^<!--(.*)-->$"#).unwrap();
-    static ref RE_NUM_EQUATION: Regex = Regex::new(r#"^\$\$<!--(?<label>.+)-->$"#).unwrap();
-    static ref RE_CODE_HERE: Regex = Regex::new(r#"```(?<lang>.+)"#).unwrap();
+    static ref RE_DIRECTIVE: Regex =
+        Regex::new(r#"^<!--\s*(?<directive>pagebreak|newpage|clearpage|appendix)\s*-->$"#).unwrap();
+    static ref RE_THEMATIC_BREAK: Regex =
+        Regex::new(r#"^ {0,3}((-[ \t]*){3,}|(\*[ \t]*){3,}|(_[ \t]*){3,})$"#).unwrap();
+    static ref RE_NUM_EQUATION: Regex = Regex::new(
+        r#"^\$\$(?<env>(equation|align|gather|multline|flalign|cases|aligned|split|gathered)\*?)?\s*(<!--(?<label>.+)-->)?$"#
+    )
+    .unwrap();
+    static ref RE_MATH_SINGLE: Regex =
+        Regex::new(r#"^\$\$(?<math>.*[^\s$].*)\$\$\s*(?<attrs>\{.*\})?$"#).unwrap();
+    static ref RE_BRACKET_MATH_SINGLE: Regex =
+        Regex::new(r#"^\\\[(?<math>.*\S.*)\\\]\s*(?<attrs>\{.*\})?$"#).unwrap();
+    static ref RE_BRACKET_MATH_OPEN: Regex = Regex::new(r#"^\\\[$"#).unwrap();
+    static ref RE_MATH_CLOSE: Regex = Regex::new(r#"^(?<math>.*?)\$\$\s*(?<attrs>\{.*\})?$"#).unwrap();
+    static ref RE_BRACKET_MATH_CLOSE: Regex =
+        Regex::new(r#"^(?<math>.*?)\\\]\s*(?<attrs>\{.*\})?$"#).unwrap();
+    static ref RE_MATH_ATTRS: Regex = Regex::new(r#"^\$\$\s*(?<attrs>\{.*\})$"#).unwrap();
+    static ref RE_CODE_FENCE: Regex =
+        Regex::new(r#"^(?<indent> {0,3})(?<fence>`{3,}|~{3,})(?<info>.*)$"#).unwrap();
+    static ref RE_CODE_ATTRS: Regex =
+        Regex::new(r#"^(?<lang>[^\s{]*)\s*(?<attrs>\{.*\})$"#).unwrap();
+    static ref RE_CODE_INCLUDE: Regex =
+        Regex::new(r#"^\|code\s+(?<path>[^\s#{]+)(#(?<region>[^\s{]+))?\s*(?<attrs>\{.*\})?$"#)
+            .unwrap();
+    static ref RE_LINE_RANGE: Regex = Regex::new(r#"^L?[0-9]*-?L?[0-9]*$"#).unwrap();
     static ref RE_CODE_FLOAT: Regex =
-        Regex::new(r#"```(?<lang>.+)<!--(?<label>.+)--><!--(?<caption>.+)-->"#).unwrap();
+        Regex::new(r#"^(`{3,}|~{3,})?(?<lang>.+)<!--(?<label>.+)--><!--(?<caption>.+)-->"#)
+            .unwrap();
 }
 /// Main entry point of the md processor.
 /// Note that this function does not actually process a single line of text.
@@ -43,33 +106,408 @@ lazy_static! {
 /// doing something with the transformed data, e.g., print to std out or write to a file.
 /// This function consumes the supplied value.
 /// Errors are printed to stderr.  A future version may return an iterator over Result objects.
-pub fn convert(lines: io::Lines<impl BufRead>) -> impl Iterator<Item = String> {
+pub fn convert(
+    lines: impl Iterator<Item = io::Result<String>>,
+    mut opts: Options,
+) -> impl Iterator<Item = String> {
     let mut state: State = State::Text;
 
+    // Inline footnotes need every definition before the first reference,
+    // so the whole file is read and the definitions taken out first.
+    let lines = match opts.footnote_mode {
+        FootnoteMode::Marks => Either::Left(lines),
+        FootnoteMode::Inline => {
+            let lines = lines
+                .filter_map(|line| line.inspect_err(|err| eprintln!("{}", err)).ok())
+                .collect();
+            let (rest, footnotes) = Footnotes::collect(lines);
+            opts.footnotes = Rc::new(footnotes);
+            Either::Right(rest.into_iter().map(Ok))
+        }
+    };
+
+    // A None after the last line signals the end of the input,
+    // so whatever is still open can be closed.
     lines
-        .map(move |res_line| {
-            res_line
+        .map(Some)
+        .chain(std::iter::once(None))
+        .filter_map(move |res_line| match res_line {
+            Some(res_line) => res_line
                 .map_err(|err| anyhow!(err))
-                .and_then(|line| state.process_line(&line))
+                .and_then(|line| state.process_line(&line, &opts))
                 .inspect_err(|err| eprintln!("{}", err))
                 .ok()
                 .map(|(new_state, processed_line)| {
                     state = new_state;
                     processed_line
-                })
+                }),
+            None => {
+                opts.footnotes.report_unused();
+                Some(state.finish(&opts))
+            }
         })
-        .filter(Option::is_some)
-        .map(Option::unwrap)
+}
+
+/// The keys of the front matter this tool reads, see `MathMacros::add_front_matter`.
+const FRONT_MATTER_KEYS: &[&str] = &["macros", "operators"];
+
+/// Split the front matter off a file's lines: YAML between a `---` line at the very
+/// start of the file and the next `---` or `...` line.
+/// The lines between are only front matter if they're YAML with a key this tool
+/// reads, since they may as well be text between two thematic breaks.
+/// Returns the front matter, empty if there's none, and the rest of the lines.
+pub fn split_front_matter(mut lines: Vec<String>) -> (Vec<String>, Vec<String>) {
+    let starts = lines.first().is_some_and(|line| line.trim_end() == "---")
+        && lines.get(1).is_some_and(|line| !line.trim().is_empty());
+    let end = lines
+        .iter()
+        .skip(1)
+        .position(|line| matches!(line.trim_end(), "---" | "..."));
+    match end {
+        Some(end) if starts && is_front_matter(&lines[1..=end]) => {
+            let rest = lines.split_off(end + 2);
+            lines.truncate(end + 1);
+            lines.remove(0);
+            (lines, rest)
+        }
+        _ => (Vec::new(), lines),
+    }
+}
+
+/// Whether some lines are YAML with a key of the front matter this tool reads.
+fn is_front_matter(lines: &[String]) -> bool {
+    match serde_yaml_ng::from_str::<serde_yaml_ng::Value>(&lines.join("\n")) {
+        Ok(serde_yaml_ng::Value::Mapping(map)) => map.keys().any(|key| {
+            key.as_str()
+                .is_some_and(|key| FRONT_MATTER_KEYS.contains(&key))
+        }),
+        _ => false,
+    }
+}
+
+/// Convert a block of markdown on its own, e.g. the definition of a footnote.
+fn convert_block(lines: &[String], opts: &Options) -> String {
+    let mut state = State::Text;
+    let mut res = String::new();
+    for line in lines {
+        match state.process_line(line, opts) {
+            Ok((next, text)) => {
+                state = next;
+                res.push_str(&text);
+            }
+            Err(err) => eprintln!("{}", err),
+        }
+    }
+    res + &state.finish(opts)
+}
+
+/// Settings that change how the markdown is translated, typically set from the command line.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// The package used to typeset code blocks
+    pub code_backend: CodeBackend,
+    /// The features of code blocks that need packages of their own, noted as
+    /// they're converted for the standalone preamble
+    pub code_features: Rc<CodeFeatures>,
+    /// Colours used by the highlight code backend
+    pub highlight_theme: HighlightTheme,
+    /// The directory of the markdown file, which included files are relative to
+    pub base_dir: PathBuf,
+    /// How deeply lists may nest, if deeper than LaTeX normally allows;
+    /// values under four are taken as four
+    pub list_depth: Option<usize>,
+    /// The label of an unchecked task list item, `$\square$` if not set
+    pub task_unchecked: Option<String>,
+    /// The label of a checked task list item, `$\boxtimes$` if not set
+    pub task_checked: Option<String>,
+    /// Titles of callout boxes, replacing the defaults in the standalone preamble
+    pub callout_titles: Vec<(Callout, String)>,
+    /// Colours of callout boxes, replacing the defaults in the standalone preamble
+    pub callout_colours: Vec<(Callout, String)>,
+    /// Whether theorems, lemmas, etc. share a counter
+    pub theorem_counters: TheoremCounters,
+    /// What theorem numbers are reset by
+    pub theorem_numbering: TheoremNumbering,
+    /// How footnotes are written out
+    pub footnote_mode: FootnoteMode,
+    /// The footnote definitions, collected before converting when footnotes are inline
+    pub footnotes: Rc<Footnotes>,
+    /// The math macros declared for the document, from front matter or a macros file
+    pub math_macros: Rc<MathMacros>,
+    /// What a thematic break, `---`, becomes
+    pub thematic_break: ThematicBreak,
+    /// The dialect of markdown
+    pub flavor: Flavor,
+    /// The tex goes in the argument of a command, e.g. a footnote, where verbatim
+    /// commands and environments break
+    pub in_argument: bool,
+}
+
+impl Options {
+    /// The options for markdown whose tex goes in the argument of a command.
+    fn in_argument(&self) -> Options {
+        Options {
+            in_argument: true,
+            ..self.clone()
+        }
+    }
+}
+
+/// The dialect of markdown, which decides how emphasis is read.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum Flavor {
+    /// This tool's own dialect: `*bold*` and `_emphasis_`
+    #[default]
+    Legacy,
+    /// CommonMark emphasis: `*emphasis*` or `_emphasis_`, `**strong**` or `__strong__`
+    Commonmark,
+    /// CommonMark, with GitHub's `~~strikethrough~~`
+    Gfm,
+    /// CommonMark, with pandoc's `~~strikeout~~` and `~subscript~`
+    Pandoc,
+}
+
+/// What a thematic break, a line of `---`, `***` or `___`, becomes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum ThematicBreak {
+    /// A rule across the page
+    #[default]
+    Hrule,
+    /// Three centred asterisks
+    Asterism,
+    /// A new page
+    Clearpage,
+}
+
+impl ThematicBreak {
+    fn tex(&self) -> &'static str {
+        match self {
+            ThematicBreak::Hrule => "\\par\\medskip\\hrule\\medskip\n",
+            ThematicBreak::Asterism => "\\begin{center}*\\quad*\\quad*\\end{center}\n",
+            ThematicBreak::Clearpage => "\\clearpage\n",
+        }
+    }
+}
+
+const DEFAULT_TASK_UNCHECKED: &str = "$\\square$";
+const DEFAULT_TASK_CHECKED: &str = "$\\boxtimes$";
+
+/// The start of a standalone tex document, up to and including `\begin{document}`.
+/// The packages loaded here cover everything the converter may emit.
+pub fn preamble(opts: &Options) -> String {
+    let mut preamble = "\\documentclass{report}\n".to_owned();
+    for package in [
+        "amsmath", "amssymb", "graphicx", "booktabs", "makecell", "siunitx", "csquotes", "enumitem",
+    ] {
+        preamble.push_str(&format!("\\usepackage{{{}}}\n", package));
+    }
+    if matches!(opts.flavor, Flavor::Gfm | Flavor::Pandoc) {
+        // For \sout, leaving \emph as it was
+        preamble.push_str("\\usepackage[normalem]{ulem}\n");
+    }
+    preamble.push_str(&opts.code_backend.preamble(&opts.code_features));
+    preamble.push_str(&callout::preamble(opts));
+    preamble.push_str(&theorem::preamble(opts));
+    preamble.push_str(&opts.math_macros.preamble());
+    if let Some(depth) = opts.list_depth {
+        preamble.push_str(&list_depth_preamble(depth.max(4)));
+    }
+    // hyperref goes last, as it redefines commands of the packages before it.
+    // Its \url, unlike url's, reads an escaped `\%` in a command's argument as `%`.
+    preamble.push_str("\\usepackage{hyperref}\n");
+    preamble.push_str("\n\\begin{document}\n\n");
+    preamble
+}
+
+/// The end of a standalone tex document.
+pub fn postamble() -> String {
+    "\n\\end{document}\n".to_owned()
+}
+
+/// The kinds of list, which may be nested within each other in any combination.
+/// Enumerated lists with different numbering are different kinds,
+/// just as a change of bullet or delimiter starts a new list in CommonMark.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ListKind {
+    Itemize,
+    Enumerate(Numbering),
+}
+
+/// How the items of an enumerated list are labelled: the style of the counter
+/// and the delimiter after it, `.` or `)`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Numbering {
+    style: NumberStyle,
+    delim: char,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum NumberStyle {
+    Arabic,
+    LowerAlpha,
+    UpperAlpha,
+    LowerRoman,
+    UpperRoman,
+}
+
+impl NumberStyle {
+    /// The enumitem label command for the counter
+    fn label(&self) -> &'static str {
+        match self {
+            NumberStyle::Arabic => "\\arabic*",
+            NumberStyle::LowerAlpha => "\\alph*",
+            NumberStyle::UpperAlpha => "\\Alph*",
+            NumberStyle::LowerRoman => "\\roman*",
+            NumberStyle::UpperRoman => "\\Roman*",
+        }
+    }
+}
+
+impl ListKind {
+    /// Open the list.  Enumerated lists that don't start at 1 or aren't labelled
+    /// `1.`, `2.`, ... get enumitem options to match the markdown.
+    fn begin(&self, start: u32) -> String {
+        match self {
+            ListKind::Itemize => "\\begin{itemize}".to_owned(),
+            ListKind::Enumerate(numbering) => {
+                let mut options = vec![];
+                if *numbering
+                    != (Numbering {
+                        style: NumberStyle::Arabic,
+                        delim: '.',
+                    })
+                {
+                    options.push(format!(
+                        "label={}{}",
+                        numbering.style.label(),
+                        numbering.delim
+                    ));
+                }
+                if start != 1 {
+                    options.push(format!("start={}", start));
+                }
+                if options.is_empty() {
+                    "\\begin{enumerate}".to_owned()
+                } else {
+                    format!("\\begin{{enumerate}}[{}]", options.join(", "))
+                }
+            }
+        }
+    }
+
+    fn end(&self) -> &'static str {
+        match self {
+            ListKind::Itemize => "\\end{itemize}",
+            ListKind::Enumerate(_) => "\\end{enumerate}",
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            ListKind::Itemize => "itemize",
+            ListKind::Enumerate(_) => "enumerate",
+        }
+    }
+}
+
+/// One open list: the indent of its item markers and what kind of list it is.
+/// The indent is measured in columns, with tabs advancing to the next multiple of four.
+/// `content` is the column the text of the current item starts at; lines indented
+/// at least that far belong to the item.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct ListLevel {
+    indent: usize,
+    content: usize,
+    kind: ListKind,
+}
+
+/// The open lists and the blocks inside the current item.
+#[derive(Clone, PartialEq)]
+struct ListState {
+    /// Open lists, outermost first
+    levels: SmallVec<[ListLevel; 4]>,
+    /// The state of the item's content, which is processed with the item's indent removed
+    inner: Box<State>,
+    /// Whether the previous line was blank, in which case the list may still go on
+    blank: bool,
+}
+
+impl ListState {
+    fn new(levels: SmallVec<[ListLevel; 4]>) -> ListState {
+        ListState {
+            levels,
+            inner: Box::new(State::Paragraph),
+            blank: false,
+        }
+    }
+}
+
+/// An open block quote.  Its content, without the `>` markers, is processed by a
+/// state machine of its own, so quotes may hold lists, code and further quotes.
+#[derive(Clone, PartialEq)]
+struct QuoteState {
+    inner: Box<State>,
+    /// The converted content so far; the quote is written out when it closes,
+    /// since the attribution at its end goes at its beginning
+    body: String,
+    /// A `-- Author` line, which is the attribution if it turns out to be the last line
+    attribution: Option<String>,
+    /// Set if the quote is a callout, `> [!NOTE]`, along with the title given after the marker
+    callout: Option<(Callout, String)>,
+}
+
+impl QuoteState {
+    fn new() -> QuoteState {
+        QuoteState {
+            inner: Box::new(State::Text),
+            body: String::new(),
+            attribution: None,
+            callout: None,
+        }
+    }
+}
+
+/// An open pandoc style fenced div, whose content is processed by a state machine
+/// of its own.  Divs nest, with the outer ones using longer fences where it helps
+/// readability.
+#[derive(Clone, PartialEq)]
+struct DivState {
+    inner: Box<State>,
+    /// The number of colons in the opening fence; the closing fence needs at least as many
+    fence: usize,
+    /// The environment named by the div's first class, if it has one
+    environment: Option<String>,
+}
+
+impl DivState {
+    fn end(&self) -> String {
+        self.environment
+            .as_ref()
+            .map(|env| format!("\\end{{{}}}\n", env))
+            .unwrap_or_default()
+    }
+}
+
+/// The body of a footnote, which goes on until a blank line, or after one,
+/// with lines indented by four columns.  Those may hold any kind of block.
+#[derive(Clone, PartialEq)]
+struct FootnoteState {
+    /// The state of the body, which is processed with its indent removed
+    inner: Box<State>,
+    /// Whether the previous line was blank, in which case the footnote may still go on
+    blank: bool,
 }
 
 /// Processing is modeled on a state machine.
 /// These are the states that we could be in.
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 enum State {
-    Ordered(SmallVec<[u8; 4]>),
-    Unordered(SmallVec<[u8; 4]>),
-    Quote,
-    Code,
+    /// Inside nested lists
+    List(ListState),
+    Quote(QuoteState),
+    /// Inside a fenced div, `::: {.class}`
+    Div(DivState),
+    Code(CodeBlock),
     Figure,
     FigureCaption,
     TableHeader,
@@ -77,31 +515,92 @@ enum State {
     TableCaption,
     Literal,
     Text,
-    FootnoteBody,
-    NumberedEquation,
-    UnnumberedEquation,
+    /// Like text, but the previous line was part of a paragraph, which can't be
+    /// interrupted by an indented code block
+    Paragraph,
+    /// Like a paragraph, but the previous line ended with a hard line break.
+    /// The `\\` goes before the next line, if the paragraph goes on.
+    LineBreak,
+    FootnoteBody(FootnoteState),
+    /// A line of text that starts a paragraph, held back in case the next line
+    /// makes it the term of a definition list
+    Term(String),
+    /// Inside a definition list; true if the previous line was blank
+    Description(bool),
+    /// A possible term after a blank line in a definition list
+    DescriptionTerm(String),
+    /// Display math, `$$` up to the closing `$$`
+    Math(MathBlock),
 }
 
 impl State {
     /// State has one function, process the line.
     /// This function determines which state we are currently in and calls the
     /// appropriate function.  It's like dynamic dispatch, except not.
-    fn process_line(&self, line: &str) -> Result<(State, String), Error> {
+    fn process_line(&self, line: &str, opts: &Options) -> Result<(State, String), Error> {
         match self {
-            State::Ordered(indents) => process_line_ordered(line, indents),
-            State::Unordered(indents) => process_line_unordered(line, indents),
-            State::Quote => process_line_quote(line),
-            State::Code => process_line_code(line),
+            State::List(list) => process_line_list(line, list, opts),
+            State::Quote(quote) => process_line_quote(line, quote, opts),
+            State::Div(div) => process_line_div(line, div, opts),
+            State::Code(block) => process_line_code(line, block, opts),
             State::Figure => process_line_figure(line),
-            State::FigureCaption => process_line_figure_caption(line),
+            State::FigureCaption => process_line_figure_caption(line, opts),
             State::TableHeader => process_line_table_header(line),
-            State::TableBody(line_every_row) => process_line_table_body(line, *line_every_row),
-            State::TableCaption => process_line_table_caption(line),
+            State::TableBody(line_every_row) => {
+                process_line_table_body(line, *line_every_row, opts)
+            }
+            State::TableCaption => process_line_table_caption(line, opts),
             State::Literal => process_literal(line),
-            State::FootnoteBody => process_footnote_body(line),
-            State::Text => process_line_text(line),
-            State::UnnumberedEquation => process_unnumbered_equation_text(line),
-            State::NumberedEquation => process_numbered_equation_text(line),
+            State::FootnoteBody(note) => process_footnote_body(line, note, opts),
+            State::Term(term) => process_line_term(line, term, false, opts),
+            State::Description(blank) => process_line_description(line, *blank, opts),
+            State::DescriptionTerm(term) => process_line_term(line, term, true, opts),
+            State::Text => process_line_text(line, opts),
+            State::Paragraph => process_line_paragraph(line, false, opts),
+            State::LineBreak => process_line_paragraph(line, true, opts),
+            State::Math(block) => process_line_math(line, block, opts),
+        }
+    }
+
+    /// Whether we are between blocks, so that any kind of block may start.
+    fn is_idle(&self) -> bool {
+        matches!(self, State::Text | State::Paragraph | State::LineBreak)
+    }
+
+    /// The input has ended; produce whatever closes the current state.
+    fn finish(&self, opts: &Options) -> String {
+        match self {
+            State::Code(block) => block.end().to_owned(),
+            State::List(list) => list.inner.finish(opts) + &close_lists(&list.levels),
+            State::Quote(quote) => close_quote(quote, opts),
+            State::FootnoteBody(note) => note.inner.finish(&opts.in_argument()) + "}\n",
+            State::Div(div) => div.inner.finish(opts) + &div.end(),
+            State::Math(block) => block.end(),
+            State::Term(term) => paragraph_line(term, opts).1,
+            State::Description(_) => "\\end{description}\n".to_owned(),
+            State::DescriptionTerm(term) => {
+                "\\end{description}\n\n".to_owned() + &paragraph_line(term, opts).1
+            }
+            State::Text | State::Paragraph | State::LineBreak => String::new(),
+            _ => {
+                // The remaining blocks end at a blank line, tables and figures
+                // after a caption, so feed blank lines until they're done.
+                let mut state = self.clone();
+                let mut text = String::new();
+                for _ in 0..4 {
+                    match state.process_line("", opts) {
+                        Ok((next, more)) => {
+                            text.push_str(&more);
+                            state = next;
+                        }
+                        Err(_) => break,
+                    }
+                    if state.is_idle() {
+                        break;
+                    }
+                }
+                text
+            }
         }
     }
 }
@@ -109,233 +608,962 @@ impl State {
 /// Process a simple string.
 /// We are not concerned with sections, tables, lists, etc here.
 /// This is just a plain old piece of text, maybe in the document body,
-/// maybe in a figure caption, maybe in a table row.
+/// maybe in a list item or a quote.
 /// It may have bold text, italics, superscripts, and so on.
 /// It may have single or double quotes.
 /// This translation to tex happens here.
-fn simple_string_process(line: &str) -> String {
-    let mut res = line.to_owned();
-    res = res.replace('&', "\\&");
-    res = RE_COMMENT.replace_all(&res, String::new()).to_string();
-    res = RE_SUPERSCRIPT
+fn simple_string_process(line: &str, opts: &Options) -> String {
+    inline_process(line, opts, opts.in_argument)
+}
+
+/// Process a simple string that ends up in the argument of a command,
+/// e.g., a caption, a footnote, or a table cell.
+/// Verbatim commands like `\verb` break there, so code is set with escaped `\texttt`.
+fn fragile_string_process(line: &str, opts: &Options) -> String {
+    inline_process(line, opts, true)
+}
+
+fn inline_process(line: &str, opts: &Options, fragile: bool) -> String {
+    // Code spans are taken out first so none of the other rules touch their contents.
+    // Each is replaced by a numbered placeholder and put back once everything else is done.
+    let (res, code_spans) = extract_code_spans(line);
+    inline_rules(&res, &code_spans, opts, fragile)
+}
+
+/// Apply the inline rules to text whose code spans have been taken out.
+fn inline_rules(
+    line: &str,
+    code_spans: &[(String, Option<String>)],
+    opts: &Options,
+    fragile: bool,
+) -> String {
+    // Inline footnotes come out next, before `^` is taken for a superscript.
+    // Their text is processed on its own and put back at the end.
+    let (mut res, notes) = extract_inline_footnotes(line);
+    for cap in RE_INLINE_MATH.captures_iter(&res) {
+        opts.math_macros.check(&cap["math"]);
+    }
+    // Footnote references likewise, so that two on a line don't look like a superscript
+    let mut marks = Vec::new();
+    res = RE_FOOTNOTE_REF
         .replace_all(&res, |cap: &Captures| {
-            format!(r"\textsuperscript{{{}}}", &cap["super"])
+            marks.push(cap["mark"].to_owned());
+            format!("\u{E004}{}\u{E005}", marks.len() - 1)
         })
         .to_string();
-    res = RE_BOLD_FONT
+    // And link targets, which go verbatim into `\url` and mustn't be escaped
+    let mut links = Vec::new();
+    res = RE_LINK
         .replace_all(&res, |cap: &Captures| {
-            format!(r"\textbf{{{}}}", &cap["bold"])
+            links.push(cap["link"].to_owned());
+            format!("[{}](\u{E006}{}\u{E007})", &cap["text"], links.len() - 1)
         })
         .to_string();
-    res = RE_MONO_FONT
+    res = res.replace('&', "\\&");
+    res = escape_percent(&res);
+    res = RE_COMMENT.replace_all(&res, String::new()).to_string();
+    res = RE_SUPERSCRIPT
         .replace_all(&res, |cap: &Captures| {
-            format!(r"\texttt{{{}}}", &cap["mono"])
+            format!(r"\textsuperscript{{{}}}", &cap["super"])
         })
         .to_string();
+    if opts.flavor == Flavor::Legacy {
+        res = RE_BOLD_FONT
+            .replace_all(&res, |cap: &Captures| {
+                format!(r"\textbf{{{}}}", &cap["bold"])
+            })
+            .to_string();
+    }
     res = RE_SINGLE_QUOTE
         .replace_all(&res, |cap: &Captures| format!("`{}'", &cap["quote"]))
         .to_string();
     res = RE_DOUBLE_QUOTE
         .replace_all(&res, |cap: &Captures| format!("``{}''", &cap["quote"]))
         .to_string();
-    res = RE_EMPH_FONT
-        .replace_all(&res, |cap: &Captures| format!(r"\emph{{{}}}", &cap["emph"]))
-        .to_string();
+    res = match opts.flavor {
+        Flavor::Legacy => RE_EMPH_FONT
+            .replace_all(&res, |cap: &Captures| format!(r"\emph{{{}}}", &cap["emph"]))
+            .to_string(),
+        flavor => commonmark_emphasis(&res, flavor),
+    };
     res = RE_LINK
         .replace_all(&res, |cap: &Captures| {
             format!(r"{} \url{{{}}}", &cap["text"], &cap["link"])
         })
         .to_string();
-    res = RE_FOOTNOTE_REF
+    res = RE_LINK_PLACEHOLDER
+        .replace_all(&res, |cap: &Captures| {
+            let link = &links[cap["idx"].parse::<usize>().unwrap()];
+            // In a command's argument `\url` needs `%` escaped after all
+            if fragile {
+                escape_percent(link)
+            } else {
+                link.to_owned()
+            }
+        })
+        .to_string();
+    res = RE_MARK_PLACEHOLDER
+        .replace_all(&res, |cap: &Captures| {
+            let mark = &marks[cap["idx"].parse::<usize>().unwrap()];
+            match opts.footnote_mode {
+                FootnoteMode::Marks => format!(r"\footnotemark[{}]", mark),
+                FootnoteMode::Inline => opts
+                    .footnotes
+                    .expand(mark, |body| convert_block(body, &opts.in_argument()))
+                    .map(|tex| format!(r"\footnote{{{}}}", tex.trim()))
+                    .unwrap_or_else(|| format!(r"[\textasciicircum{{}}{}]", mark)),
+            }
+        })
+        .to_string();
+    res = RE_PLACEHOLDER
+        .replace_all(&res, |cap: &Captures| {
+            let (code, lang) = &code_spans[cap["idx"].parse::<usize>().unwrap()];
+            inline_code(opts, code, lang.as_deref(), fragile)
+        })
+        .to_string();
+    res = RE_NOTE_PLACEHOLDER
         .replace_all(&res, |cap: &Captures| {
-            format!(r"\footnotemark[{}]", &cap["mark"])
+            let note = &notes[cap["idx"].parse::<usize>().unwrap()];
+            format!(
+                r"\footnote{{{}}}",
+                inline_rules(note, code_spans, opts, true)
+            )
         })
         .to_string();
 
     res
 }
 
-fn process_line_ordered(line: &str, indents: &SmallVec<[u8; 4]>) -> Result<(State, String), Error> {
-    let trimmed = line.trim();
-    if trimmed.is_empty() {
-        // Close out all open itemizes
-        Ok((
-            State::Text,
-            indents.iter().map(|_| "\\end{enumerate}").join("\n") + "\n",
-        ))
+/// Emphasis and the flavor's other marks for the CommonMark based flavors.
+/// Inline math is kept out of it, where `_` is a subscript.
+/// A `~` left over in CommonMark is a tilde, not tex's non-breaking space.
+fn commonmark_emphasis(line: &str, flavor: Flavor) -> String {
+    let mut maths = Vec::new();
+    let res = RE_INLINE_MATH
+        .replace_all(line, |cap: &Captures| {
+            maths.push(cap[0].to_owned());
+            format!("\u{E008}{}\u{E009}", maths.len() - 1)
+        })
+        .to_string();
+    let mut res = emphasis(&res);
+    if flavor == Flavor::Commonmark {
+        res = res.replace('~', "\\textasciitilde{}");
     } else {
-        if let Some(cap) = RE_START_ENUMERATE.captures(trimmed) {
-            // Line starts with a '* ' or '+ ' or '- ', which is an itemized list.
-
-            let indent_u = line.chars().take_while(|ch| ch.is_whitespace()).count();
-            if indent_u > u8::max_value() as usize {
-                bail!(
-                    "Leading indent cannot be more than {}, however I got {}.",
-                    u8::max_value(),
-                    indent_u
-                );
-            }
-            let indent = indent_u as u8;
-            let prev_indent = indents
-                .last()
-                .expect("This function shouldn't be called with an empty indents vec.");
-
-            if &indent == prev_indent {
-                // indent hasn't changed
-                let mut item = r#"\item "#.to_owned();
-                item.push_str(&simple_string_process(&cap["item"]));
-                item.push('\n');
-                Ok((State::Ordered(indents.to_owned()), item))
-            } else if &indent > prev_indent {
-                // indent increased
-                if indents.len() == indents.capacity() {
-                    bail!("Exceeded this tool's hard-coded limit on the level of nesting of enumerate components.");
+        res = RE_STRIKEOUT
+            .replace_all(&res, |cap: &Captures| {
+                format!(r"\sout{{{}}}", &cap["strike"])
+            })
+            .to_string();
+    }
+    if flavor == Flavor::Pandoc {
+        res = RE_SUBSCRIPT
+            .replace_all(&res, |cap: &Captures| {
+                format!(r"\textsubscript{{{}}}", &cap["sub"])
+            })
+            .to_string();
+    }
+    RE_MATH_PLACEHOLDER
+        .replace_all(&res, |cap: &Captures| {
+            maths[cap["idx"].parse::<usize>().unwrap()].clone()
+        })
+        .to_string()
+}
+
+/// Pull pandoc style inline footnotes, `^[the note]`, out of a line of markdown.
+/// The note may contain brackets of its own, as long as they're balanced or escaped.
+/// Returns the line with the notes replaced by placeholders, and the notes themselves.
+fn extract_inline_footnotes(line: &str) -> (String, Vec<String>) {
+    let mut res = String::with_capacity(line.len());
+    let mut notes = Vec::new();
+    let mut rest = line;
+    while let Some(start) = rest.find("^[") {
+        res.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let mut depth = 0;
+        let mut prev = None;
+        let mut close = None;
+        for (i, ch) in after.char_indices() {
+            match ch {
+                '[' if prev != Some('\\') => depth += 1,
+                ']' if prev != Some('\\') && depth == 0 => {
+                    close = Some(i);
+                    break;
                 }
-                let mut sub_list = "\\begin{enumerate}\n".to_owned();
-                sub_list.push_str("\\item ");
-                sub_list.push_str(&simple_string_process(&cap["item"]));
-                sub_list.push('\n');
-                let next_indents = {
-                    let mut tmp = indents.to_owned();
-                    tmp.push(indent);
-                    tmp
-                };
-                Ok((State::Ordered(next_indents), sub_list))
-            } else
-            /* if indent < current_index */
-            {
-                // indent decreased
-                // close out the current list and then recursively call this function.
-                // Why recursion?
-                // Imagine our indents are [2, 4] and the current indent is 3.
-                // We close this one at 4 but then start a new one.
-                if indents.len() <= 1 {
-                    // We may close several open enumerates, however if we end up with something like
-                    // indents == [4, 8] and the current indent is 2, this is an error.
-                    bail!("Indent level cannot be smaller than the initial indent");
+                ']' if prev != Some('\\') => depth -= 1,
+                _ => {}
+            }
+            prev = Some(ch);
+        }
+        match close {
+            Some(end) => {
+                res.push_str(&format!("\u{E002}{}\u{E003}", notes.len()));
+                notes.push(after[..end].to_owned());
+                rest = &after[end + 1..];
+            }
+            None => {
+                // No closing bracket, so it isn't a footnote
+                res.push_str("^[");
+                rest = after;
+            }
+        }
+    }
+    res.push_str(rest);
+    (res, notes)
+}
+
+/// Pull the code spans out of a line of markdown.
+/// A span opens with a run of backticks and closes with a run of the same length.
+/// One space is stripped from each end if both are there, so that code starting or
+/// ending with a backtick can be written.  A `{.lang}` attribute right after the span
+/// gives its language.
+/// Returns the line with the spans replaced by placeholders, and the spans themselves.
+fn extract_code_spans(line: &str) -> (String, Vec<(String, Option<String>)>) {
+    let mut res = String::with_capacity(line.len());
+    let mut spans = Vec::new();
+    let mut rest = line;
+    while let Some(start) = rest.find('`') {
+        res.push_str(&rest[..start]);
+        let ticks = rest[start..].len() - rest[start..].trim_start_matches('`').len();
+        let after = &rest[start + ticks..];
+        // Find a closing run of exactly the same length
+        let mut search = 0;
+        let mut close = None;
+        while let Some(pos) = after[search..].find('`') {
+            let run_start = search + pos;
+            let run = after[run_start..].len() - after[run_start..].trim_start_matches('`').len();
+            if run == ticks {
+                close = Some(run_start);
+                break;
+            }
+            search = run_start + run;
+        }
+        match close {
+            Some(end) => {
+                let mut code = &after[..end];
+                if code.len() >= 2
+                    && code.starts_with(' ')
+                    && code.ends_with(' ')
+                    && !code.trim().is_empty()
+                {
+                    code = &code[1..code.len() - 1];
                 }
-                let list_close = "\\end{enumerate}\n".to_owned();
-                let next_indents = {
-                    let mut tmp = indents.to_owned();
-                    tmp.pop();
-                    tmp
-                };
-                let subprocessing = process_line_ordered(line, &next_indents)?;
-                Ok((subprocessing.0, list_close + &subprocessing.1))
+                rest = &after[end + ticks..];
+                let lang = RE_CODE_SPAN_LANG.captures(rest).map(|cap| {
+                    let lang = cap["lang"].to_owned();
+                    rest = &rest[cap[0].len()..];
+                    lang
+                });
+                res.push_str(&format!("\u{E000}{}\u{E001}", spans.len()));
+                spans.push((code.to_owned(), lang));
+            }
+            None => {
+                // No closing run, so the backticks are literal
+                res.push_str(&rest[start..start + ticks]);
+                rest = after;
             }
+        }
+    }
+    res.push_str(rest);
+    (res, spans)
+}
+
+/// Escape percent signs so they aren't read as the start of a tex comment.
+/// Percent signs that are already escaped are left alone.
+fn escape_percent(line: &str) -> String {
+    let mut res = String::with_capacity(line.len());
+    let mut prev = None;
+    for ch in line.chars() {
+        if ch == '%' && prev != Some('\\') {
+            res.push('\\');
+        }
+        res.push(ch);
+        prev = Some(ch);
+    }
+    res
+}
+
+/// A line that starts a list item.
+struct ListItem {
+    kind: ListKind,
+    /// The number of the item, which only matters if it starts an enumerated list
+    start: u32,
+    text: String,
+    /// The width of the marker including the spaces after it
+    width: usize,
+}
+
+/// Find the list item that starts a line, if there is one.
+/// `prev` is the kind of the innermost open list, which decides whether a marker
+/// like `i.` continues an alphabetic list or starts a roman one.
+fn list_item(trimmed: &str, prev: Option<ListKind>) -> Option<ListItem> {
+    let (kind, start, cap) = if let Some(cap) = RE_START_ITEMIZE.captures(trimmed) {
+        // Line starts with a '* ' or '+ ' or '- ', which is an itemized list.
+        (ListKind::Itemize, 1, cap)
+    } else {
+        // Line starts with a number, letter or roman numeral and a period or parenthesis.
+        // This is an enumerated list
+        let cap = RE_START_ENUMERATE.captures(trimmed)?;
+        let delim = cap["delim"].chars().next().unwrap_or('.');
+        let (style, start) = parse_list_number(&cap["marker"], delim, prev)?;
+        let upper = matches!(style, NumberStyle::UpperAlpha | NumberStyle::UpperRoman);
+        if upper && delim == '.' && !cap["item"].starts_with(' ') {
+            // Like pandoc, require two spaces after a capital letter or numeral and
+            // a period, so that a paragraph starting with an initial, or a heading
+            // like `I. Introduction`, isn't taken for a list
+            return None;
+        }
+        (ListKind::Enumerate(Numbering { style, delim }), start, cap)
+    };
+    let item = &cap["item"];
+    Some(ListItem {
+        kind,
+        start,
+        text: item.trim_start().to_owned(),
+        width: trimmed.len() - item.trim_start().len(),
+    })
+}
+
+/// The `\\item` for a list item's text.
+/// GFM task list items, starting `[ ]` or `[x]`, get a checkbox as the item's label.
+fn list_item_text(text: &str, opts: &Options) -> String {
+    if let Some(cap) = RE_TASK_ITEM.captures(text) {
+        let symbol = if &cap["mark"] == " " {
+            opts.task_unchecked
+                .as_deref()
+                .unwrap_or(DEFAULT_TASK_UNCHECKED)
         } else {
-            // Continuation of the current item
-            Ok((
-                State::Ordered(indents.to_owned()),
-                simple_string_process(trimmed) + "\n",
-            ))
+            opts.task_checked.as_deref().unwrap_or(DEFAULT_TASK_CHECKED)
+        };
+        format!(
+            "\\item[{}] {}\n",
+            symbol,
+            simple_string_process(&cap["text"], opts)
+        )
+    } else {
+        let text = simple_string_process(text, opts);
+        // A bracket straight after \item would be read as the item's label
+        let sep = if text.starts_with('[') { " {}" } else { " " };
+        format!("\\item{}{}\n", sep, text)
+    }
+}
+
+/// The style and value of an enumerated list's marker, without its delimiter.
+/// Single letters are alphabetic, except for `i`, `v` and `x`, which are roman
+/// unless they continue an alphabetic list.
+fn parse_list_number(
+    marker: &str,
+    delim: char,
+    prev: Option<ListKind>,
+) -> Option<(NumberStyle, u32)> {
+    if let Ok(number) = marker.parse() {
+        return Some((NumberStyle::Arabic, number));
+    }
+    let lower = marker.chars().all(|ch| ch.is_ascii_lowercase());
+    let (alpha, roman) = if lower {
+        (NumberStyle::LowerAlpha, NumberStyle::LowerRoman)
+    } else {
+        (NumberStyle::UpperAlpha, NumberStyle::UpperRoman)
+    };
+    let mut chars = marker.chars();
+    if let (Some(letter), None) = (chars.next(), chars.next()) {
+        let continues_alpha = prev
+            == Some(ListKind::Enumerate(Numbering {
+                style: alpha,
+                delim,
+            }));
+        if continues_alpha || !"ivxIVX".contains(letter) {
+            let value = letter.to_ascii_lowercase() as u32 - 'a' as u32 + 1;
+            return Some((alpha, value));
         }
     }
+    parse_roman(marker).map(|value| (roman, value))
 }
-fn process_line_unordered(
+
+/// The value of a roman numeral, in either case.
+/// Only numerals written the usual way count, so that a word made of the same
+/// letters, like `civil` or `mix`, isn't taken for one.
+fn parse_roman(numeral: &str) -> Option<u32> {
+    let digits = numeral
+        .chars()
+        .map(|ch| match ch.to_ascii_lowercase() {
+            'i' => Some(1),
+            'v' => Some(5),
+            'x' => Some(10),
+            'l' => Some(50),
+            'c' => Some(100),
+            'd' => Some(500),
+            'm' => Some(1000),
+            _ => None,
+        })
+        .collect::<Option<Vec<u32>>>()?;
+    let mut value = 0;
+    for (i, digit) in digits.iter().enumerate() {
+        match digits.get(i + 1) {
+            Some(next) if next > digit => value -= *digit as i64,
+            _ => value += *digit as i64,
+        }
+    }
+    u32::try_from(value)
+        .ok()
+        .filter(|value| *value > 0 && to_roman(*value) == numeral.to_ascii_lowercase())
+}
+
+/// A number as a lowercase roman numeral.
+fn to_roman(mut value: u32) -> String {
+    const NUMERALS: [(u32, &str); 13] = [
+        (1000, "m"),
+        (900, "cm"),
+        (500, "d"),
+        (400, "cd"),
+        (100, "c"),
+        (90, "xc"),
+        (50, "l"),
+        (40, "xl"),
+        (10, "x"),
+        (9, "ix"),
+        (5, "v"),
+        (4, "iv"),
+        (1, "i"),
+    ];
+    let mut res = String::new();
+    for (step, numeral) in NUMERALS {
+        while value >= step {
+            res.push_str(numeral);
+            value -= step;
+        }
+    }
+    res
+}
+
+/// LaTeX allows four levels of each kind of list, and six levels in all.
+/// With enumitem's `\setlistdepth` and `\renewlist` both limits can be raised,
+/// which the standalone preamble does when a list depth is configured.
+fn check_list_depth(levels: &[ListLevel], opts: &Options) -> Result<(), Error> {
+    // A limit below the four levels LaTeX allows anyway doesn't lower it
+    let per_kind = opts.list_depth.map_or(4, |depth| depth.max(4));
+    let total = opts.list_depth.map_or(6, |depth| depth.max(6));
+    for name in ["itemize", "enumerate"] {
+        let depth = levels.iter().filter(|l| l.kind.name() == name).count();
+        if depth > per_kind {
+            bail!(
+                "Lists are nested {} {} levels deep, but LaTeX only allows {}.  Use --list-depth to raise the limit; the standalone preamble then configures enumitem for it.",
+                depth,
+                name,
+                per_kind
+            );
+        }
+    }
+    if levels.len() > total {
+        bail!(
+            "Lists are nested {} levels deep, but LaTeX only allows {}.  Use --list-depth to raise the limit; the standalone preamble then configures enumitem for it.",
+            levels.len(),
+            total
+        );
+    }
+    Ok(())
+}
+
+/// An item nested deeper than lists may go, which is reported and kept
+/// as another item of the deepest list allowed rather than losing its text.
+fn too_deep_item(err: Error, list: &ListState, item: String) -> Result<(State, String), Error> {
+    eprintln!("{}", err);
+    Ok((State::List(ListState::new(list.levels.to_owned())), item))
+}
+
+/// The preamble lines that let lists nest `depth` levels deep, using enumitem.
+/// Levels beyond LaTeX's usual four get labels cycling through the usual ones.
+fn list_depth_preamble(depth: usize) -> String {
+    let mut preamble = format!("\\setlistdepth{{{}}}\n", depth.max(6));
+    preamble.push_str(&format!("\\renewlist{{itemize}}{{itemize}}{{{}}}\n", depth));
+    preamble.push_str(&format!(
+        "\\renewlist{{enumerate}}{{enumerate}}{{{}}}\n",
+        depth
+    ));
+    let itemize_labels = ["\\textbullet", "--", "*", "\\textperiodcentered"];
+    let enumerate_labels = ["\\arabic*.", "(\\alph*)", "\\roman*.", "\\Alph*."];
+    for level in 5..=depth {
+        preamble.push_str(&format!(
+            "\\setlist[itemize,{}]{{label={}}}\n",
+            level,
+            itemize_labels[(level - 1) % 4]
+        ));
+        preamble.push_str(&format!(
+            "\\setlist[enumerate,{}]{{label={}}}\n",
+            level,
+            enumerate_labels[(level - 1) % 4]
+        ));
+    }
+    preamble
+}
+
+/// Close every open list, innermost first.
+fn close_lists(levels: &[ListLevel]) -> String {
+    levels.iter().rev().map(|l| l.kind.end()).join("\n") + "\n"
+}
+
+fn process_line_list(
     line: &str,
-    indents: &SmallVec<[u8; 4]>,
+    list: &ListState,
+    opts: &Options,
 ) -> Result<(State, String), Error> {
     let trimmed = line.trim();
+    let indent = indent_width(line);
+    let prev = list
+        .levels
+        .last()
+        .expect("This function shouldn't be called with an empty levels vec.");
+
+    if !list.inner.is_idle() {
+        // Inside a block in the current item, e.g. a code block or a quote.
+        // Blank lines and lines indented to the item's content belong to the block.
+        if trimmed.is_empty() && matches!(*list.inner, State::Term(_)) {
+            // The held back line was a paragraph of the item; the list decides
+            // what the blank line means
+            let next = ListState {
+                inner: Box::new(State::Text),
+                blank: true,
+                ..list.clone()
+            };
+            return Ok((State::List(next), list.inner.finish(opts)));
+        }
+        // A held back term doesn't get to swallow the next item, though.
+        let term_before_item =
+            matches!(*list.inner, State::Term(_)) && list_item(trimmed, Some(prev.kind)).is_some();
+        if (trimmed.is_empty() || indent >= prev.content) && !term_before_item {
+            let (inner, text) = list
+                .inner
+                .process_line(&strip_indent(line, prev.content), opts)?;
+            let next = ListState {
+                inner: Box::new(inner),
+                blank: trimmed.is_empty(),
+                ..list.clone()
+            };
+            return Ok((State::List(next), text));
+        }
+        // Anything else ends the block, just like the end of the input would
+        let text = list.inner.finish(opts);
+        let next = ListState {
+            inner: Box::new(State::Text),
+            ..list.clone()
+        };
+        let (state, more) = process_line_list(line, &next, opts)?;
+        return Ok((state, text + &more));
+    }
+
     if trimmed.is_empty() {
-        // Close out all open itemizes
-        Ok((
-            State::Text,
-            indents.iter().map(|_| "\\end{itemize}").join("\n") + "\n",
-        ))
-    } else {
-        if let Some(cap) = RE_START_ITEMIZE.captures(trimmed) {
-            // Line starts with a '* ' or '+ ' or '- ', which is an itemized list.
-
-            let indent_u = line.chars().take_while(|ch| ch.is_whitespace()).count();
-            if indent_u > u8::max_value() as usize {
-                bail!(
-                    "Leading indent cannot be more than {}, however I got {}.",
-                    u8::max_value(),
-                    indent_u
-                );
+        // The list may carry on after a blank line, with another item or
+        // another paragraph of this one, so don't close anything yet
+        let next = ListState {
+            inner: Box::new(State::Text),
+            blank: true,
+            ..list.clone()
+        };
+        Ok((State::List(next), String::new()))
+    } else if indent < prev.content && RE_THEMATIC_BREAK.is_match(trimmed) {
+        // A break rather than an item, `* * *`, which ends the lists
+        let (state, text) = process_line_text(trimmed, opts)?;
+        Ok((state, close_lists(&list.levels) + &text))
+    } else if let Some(ListItem {
+        kind,
+        start,
+        text,
+        width,
+    }) = list_item(trimmed, Some(prev.kind))
+    {
+        let content = indent + width;
+        let mut item = list_item_text(&text, opts);
+
+        if indent == prev.indent && kind == prev.kind {
+            // indent hasn't changed, another item in the same list
+            let mut next_levels = list.levels.to_owned();
+            next_levels.last_mut().unwrap().content = content;
+            Ok((State::List(ListState::new(next_levels)), item))
+        } else if indent == prev.indent {
+            // indent hasn't changed, but the kind of list has;
+            // close the current list and start the other kind in its place
+            let mut next_levels = list.levels.to_owned();
+            next_levels.pop();
+            next_levels.push(ListLevel {
+                indent,
+                content,
+                kind,
+            });
+            if let Err(err) = check_list_depth(&next_levels, opts) {
+                return too_deep_item(err, list, item);
             }
-            let indent = indent_u as u8;
-            let prev_indent = indents
-                .last()
-                .expect("This function shouldn't be called with an empty indents vec.");
-
-            if &indent == prev_indent {
-                // indent hasn't changed
-                let mut item = r#"\item "#.to_owned();
-                item.push_str(&simple_string_process(&cap["item"]));
-                item.push('\n');
-                Ok((State::Unordered(indents.to_owned()), item))
-            } else if &indent > prev_indent {
-                // indent increased
-                if indents.len() == indents.capacity() {
-                    bail!("Exceeded this tool's hard-coded limit on the level of nesting of itemize components.");
-                }
-                let mut sub_list = "\\begin{itemize}\n".to_owned();
-                sub_list.push_str("\\item ");
-                sub_list.push_str(&simple_string_process(&cap["item"]));
-                sub_list.push('\n');
-                let next_indents = {
-                    let mut tmp = indents.to_owned();
-                    tmp.push(indent);
-                    tmp
-                };
-                Ok((State::Unordered(next_indents), sub_list))
-            } else
-            /* if indent < current_index */
-            {
-                // indent decreased
-                // close out the current list and then recursively call this function.
-                // Why recursion?
-                // Imagine our indents are [2, 4] and the current indent is 3.
-                // We close this one at 4 but then start a new one.
-                if indents.len() <= 1 {
-                    // We may close several open itemizes, however if we end up with something like
-                    // indents == [4, 8] and the current indent is 2, this is an error.
-                    bail!("Indent level cannot be smaller than the initial indent");
-                }
-                let list_close = "\\end{itemize}\n".to_owned();
-                let next_indents = {
-                    let mut tmp = indents.to_owned();
-                    tmp.pop();
-                    tmp
-                };
-                let subprocessing = process_line_unordered(line, &next_indents)?;
-                Ok((subprocessing.0, list_close + &subprocessing.1))
+            item = format!("{}\n{}\n{}", prev.kind.end(), kind.begin(start), item);
+            Ok((State::List(ListState::new(next_levels)), item))
+        } else if indent > prev.indent {
+            // indent increased
+            let mut next_levels = list.levels.to_owned();
+            next_levels.push(ListLevel {
+                indent,
+                content,
+                kind,
+            });
+            if let Err(err) = check_list_depth(&next_levels, opts) {
+                return too_deep_item(err, list, item);
             }
-        } else {
-            // Continuation of the current item
             Ok((
-                State::Unordered(indents.to_owned()),
-                simple_string_process(trimmed) + "\n",
+                State::List(ListState::new(next_levels)),
+                format!("{}\n{}", kind.begin(start), item),
             ))
+        } else
+        /* if indent < prev.indent */
+        {
+            // indent decreased
+            // close out the current list and then recursively call this function.
+            // Why recursion?
+            // Imagine our indents are [2, 4] and the current indent is 3.
+            // We close this one at 4 but then start a new one.
+            let mut next_levels = list.levels.to_owned();
+            if next_levels.len() <= 1 {
+                // We may close several open lists, and if we end up with something like
+                // indents == [4, 8] and the current indent is 2, the item is still a
+                // sibling of the first one, which now sets the list's indent.
+                next_levels[0].indent = indent;
+                return process_line_list(line, &ListState::new(next_levels), opts);
+            }
+            let list_close = format!("{}\n", prev.kind.end());
+            next_levels.pop();
+            let subprocessing = process_line_list(line, &ListState::new(next_levels), opts)?;
+            Ok((subprocessing.0, list_close + &subprocessing.1))
         }
+    } else if indent >= prev.content {
+        // Content of the current item, which may be any kind of block.
+        // After a blank line it starts a new paragraph of the item.
+        let (inner, text) = list
+            .inner
+            .process_line(&strip_indent(line, prev.content), opts)?;
+        let next = ListState {
+            inner: Box::new(inner),
+            blank: false,
+            ..list.clone()
+        };
+        let text = if list.blank {
+            "\n".to_owned() + &text
+        } else {
+            text
+        };
+        Ok((State::List(next), text))
+    } else if list.blank {
+        // A line that isn't indented after a blank line ends the lists
+        let (state, text) = process_line_text(line, opts)?;
+        Ok((state, close_lists(&list.levels) + "\n" + &text))
+    } else {
+        // A lazy continuation of the current item
+        Ok((
+            State::List(list.clone()),
+            simple_string_process(trimmed, opts) + "\n",
+        ))
+    }
+}
+/// The content of a line of a block quote, i.e. the line without the `>`
+/// and the space after it.  None if the line isn't part of a quote.
+fn quote_content(line: &str) -> Option<&str> {
+    if indent_width(line) >= 4 {
+        return None;
     }
+    let rest = line.trim_start().strip_prefix('>')?;
+    Some(rest.strip_prefix(' ').unwrap_or(rest))
 }
-fn process_line_quote(line: &str) -> Result<(State, String), Error> {
+
+fn process_line_quote(
+    line: &str,
+    quote: &QuoteState,
+    opts: &Options,
+) -> Result<(State, String), Error> {
     let trimmed = line.trim();
-    if trimmed.is_empty() {
-        Ok((State::Text, "\\end{displayquote}\n\n".to_owned()))
+    let mut quote = quote.clone();
+    let in_paragraph = matches!(
+        *quote.inner,
+        State::Text | State::Paragraph | State::LineBreak | State::Term(_)
+    );
+    if let Some(content) = quote_content(line) {
+        if let Some(attribution) = quote.attribution.take() {
+            // Something follows the attribution, so it was just text
+            let (inner, text) = quote.inner.process_line(&attribution, opts)?;
+            quote.inner = Box::new(inner);
+            quote.body.push_str(&text);
+        }
+        // Any line but verbatim content may be the attribution; if more follows,
+        // it's processed as the content it would have been
+        let verbatim = matches!(*quote.inner, State::Code(_) | State::Math(_));
+        let callout = RE_CALLOUT
+            .captures(content.trim())
+            .and_then(|cap| Some((Callout::parse(&cap["kind"])?, cap["title"].to_owned())));
+        if quote.body.is_empty() && *quote.inner == State::Text && callout.is_some() {
+            // The marker on the quote's first line makes it a callout
+            quote.callout = callout;
+        } else if quote.callout.is_none()
+            && !verbatim
+            && RE_ATTRIBUTION.is_match(content.trim())
+            && !RE_THEMATIC_BREAK.is_match(content)
+        {
+            quote.attribution = Some(content.to_owned());
+        } else {
+            let (inner, text) = quote.inner.process_line(content, opts)?;
+            quote.inner = Box::new(inner);
+            quote.body.push_str(&text);
+        }
+        Ok((State::Quote(quote), String::new()))
+    } else if trimmed.is_empty() {
+        Ok((State::Text, close_quote(&quote, opts) + "\n"))
+    } else if in_paragraph && quote.attribution.is_none() {
+        // A lazy continuation of a paragraph in the quote
+        quote.body.push_str(&quote.inner.finish(opts));
+        quote.body.push_str(&simple_string_process(trimmed, opts));
+        quote.body.push('\n');
+        quote.inner = Box::new(State::Paragraph);
+        Ok((State::Quote(quote), String::new()))
+    } else {
+        let (state, text) = process_line_text(line, opts)?;
+        Ok((state, close_quote(&quote, opts) + "\n" + &text))
+    }
+}
+
+/// Open a fenced div, e.g. `::: {.theorem #thm:main title="Fermat"}`.
+/// The first class names the environment and the id becomes its label.
+/// Theorems and the like may be abbreviated, `.thm`, and made `.unnumbered`.
+/// A `title` is the environment's optional argument, `[Fermat]`, which theorems
+/// take as their note; other keys have no place to go, so they're warned about.
+fn open_div(fence: usize, attrs: &str, opts: &Options) -> Result<(State, String), Error> {
+    let attrs = if attrs.starts_with('{') {
+        Attributes::parse(attrs)?
+    } else {
+        Attributes {
+            classes: vec![attrs.to_owned()],
+            ..Attributes::default()
+        }
+    };
+    let environment = attrs
+        .classes
+        .first()
+        .map(|class| theorem::environment(class, attrs.has_class(&["unnumbered"])));
+    let mut begin = String::new();
+    if let Some(env) = &environment {
+        begin.push_str(&format!("\\begin{{{}}}", env));
+        let mut titled = false;
+        for (key, value) in &attrs.pairs {
+            if key == "title" && !titled {
+                begin.push_str(&format!("[{}]", fragile_string_process(value, opts)));
+                titled = true;
+            } else {
+                eprintln!(
+                    "The {} environment has no use for the attribute {}={}; ignoring it.",
+                    env, key, value
+                );
+            }
+        }
+    }
+    if let Some(id) = &attrs.id {
+        begin.push_str(&format!("\\label{{{}}}", id));
+    }
+    if !begin.is_empty() {
+        begin.push('\n');
+    }
+    let div = DivState {
+        inner: Box::new(State::Text),
+        fence,
+        environment,
+    };
+    Ok((State::Div(div), begin))
+}
+
+fn process_line_div(line: &str, div: &DivState, opts: &Options) -> Result<(State, String), Error> {
+    // Nested divs and code blocks get the first look at fences
+    let closes = !matches!(*div.inner, State::Div(_) | State::Code(_))
+        && RE_DIV_CLOSE
+            .captures(line)
+            .is_some_and(|cap| cap["fence"].len() >= div.fence);
+    if closes {
+        Ok((State::Text, div.inner.finish(opts) + &div.end()))
     } else {
-        if trimmed.starts_with("> ") {
-            Ok((State::Quote, simple_string_process(&trimmed[2..]) + "\n"))
-        } else if trimmed.starts_with(">") {
-            Ok((State::Quote, simple_string_process(&trimmed[1..]) + "\n"))
+        let (inner, text) = div.inner.process_line(line, opts)?;
+        let div = DivState {
+            inner: Box::new(inner),
+            ..div.clone()
+        };
+        Ok((State::Div(div), text))
+    }
+}
+
+/// Write out a quote, with its attribution if it has one, or as a callout box.
+fn close_quote(quote: &QuoteState, opts: &Options) -> String {
+    let body = quote.body.clone() + &quote.inner.finish(opts);
+    if let Some((callout, title)) = &quote.callout {
+        let title = if title.is_empty() {
+            String::new()
         } else {
-            Ok((State::Quote, simple_string_process(trimmed) + "\n"))
+            format!("[{}]", fragile_string_process(title, opts))
+        };
+        return format!(
+            "\\begin{{{}}}{}\n{}\n\\end{{{}}}\n",
+            callout.environment(),
+            title,
+            body.trim_end_matches('\n'),
+            callout.environment()
+        );
+    }
+    let attribution = quote
+        .attribution
+        .as_ref()
+        .and_then(|line| RE_ATTRIBUTION.captures(line.trim()))
+        .map(|cap| format!("[{}]", fragile_string_process(&cap["who"], opts)))
+        .unwrap_or_default();
+    format!(
+        "\\begin{{displayquote}}{}\n{}\n\\end{{displayquote}}\n",
+        attribution,
+        body.trim_end_matches('\n')
+    )
+}
+fn process_line_code(
+    line: &str,
+    block: &CodeBlock,
+    opts: &Options,
+) -> Result<(State, String), Error> {
+    match &block.fence {
+        Some(fence) if fence.is_closed_by(line) => Ok((State::Text, block.end().to_owned())),
+        Some(_) if block.skipped => Ok((State::Code(block.clone()), String::new())),
+        Some(_) if block.included => {
+            eprintln!(
+                "Ignoring a line in a code block whose contents are included from a file.\n{}",
+                line
+            );
+            Ok((State::Code(block.clone()), String::new()))
+        }
+        Some(fence) => {
+            // Don't do simple processing here because this is verbatim code.
+            // The block adds the newline, and colours the code if highlighting.
+            let mut block = block.clone();
+            let code = block.line(&strip_indent(line, fence.indent));
+            Ok((State::Code(block), code))
+        }
+        None if line.trim().is_empty() => {
+            // Blank lines only belong to an indented block if more code follows them
+            let mut block = block.clone();
+            block.pending_blank_lines += 1;
+            Ok((State::Code(block), String::new()))
+        }
+        None if indent_width(line) >= 4 => {
+            let mut block = block.clone();
+            let mut code = String::new();
+            for _ in 0..block.pending_blank_lines {
+                code.push_str(&block.line(""));
+            }
+            block.pending_blank_lines = 0;
+            code.push_str(&block.line(&strip_indent(line, 4)));
+            Ok((State::Code(block), code))
+        }
+        None => {
+            // Any line that isn't indented ends an indented block
+            let mut text = block.end().to_owned();
+            if block.pending_blank_lines > 0 {
+                text.push('\n');
+            }
+            let (state, processed) = process_line_text(line, opts)?;
+            Ok((state, text + &processed))
+        }
+    }
+}
+
+/// The width of a line's leading whitespace, with tabs advancing to the next multiple of four.
+pub fn indent_width(line: &str) -> usize {
+    let mut width = 0;
+    for ch in line.chars() {
+        match ch {
+            ' ' => width += 1,
+            '\t' => width += 4 - width % 4,
+            _ => break,
+        }
+    }
+    width
+}
+
+/// Remove up to `width` columns of leading whitespace.
+/// A tab that straddles the boundary is replaced by the spaces left over.
+pub fn strip_indent(line: &str, width: usize) -> String {
+    let mut col = 0;
+    for (idx, ch) in line.char_indices() {
+        if col >= width {
+            return line[idx..].to_owned();
+        }
+        match ch {
+            ' ' => col += 1,
+            '\t' => {
+                let next = col + 4 - col % 4;
+                if next > width {
+                    return " ".repeat(next - width) + &line[idx + 1..];
+                }
+                col = next;
+            }
+            _ => return line[idx..].to_owned(),
         }
     }
+    String::new()
 }
-fn process_line_code(line: &str) -> Result<(State, String), Error> {
-    if line == "```" {
-        Ok((State::Text, "\\end{lstlisting}\n".to_owned()))
+
+/// Open a code block holding lines included from a file.
+/// The returned text has the opening tex and all of the code;
+/// the block supplies the closing tex.
+/// Without a language on the fence or in the attributes,
+/// it is guessed from the file extension.
+fn open_included_code_block(
+    opts: &Options,
+    lang: &str,
+    path: &str,
+    attrs: &Attributes,
+    code: &[String],
+) -> Result<(String, CodeBlock), Error> {
+    let mut spec = CodeSpec::from_attributes(lang, attrs)?;
+    if spec.lang.is_empty() {
+        spec.lang = language_for_path(path).to_owned();
+    }
+    spec.caption = spec.caption.map(|c| fragile_string_process(&c, opts));
+    let (mut text, mut block) = open_code_block(opts, &spec);
+    for line in code {
+        text.push_str(&block.line(line));
+    }
+    block.included = true;
+    Ok((text, block))
+}
+
+/// Open a fenced code block from the fence's info string, which may give the
+/// language, attributes, a caption or a file to include.
+fn open_fenced_code_block(info: &str, opts: &Options) -> Result<(String, CodeBlock), Error> {
+    if let Some(cap) = RE_CODE_ATTRS.captures(info) {
+        let attrs = Attributes::parse(&cap["attrs"])?;
+        if let Some(path) = attrs.get(&["include"]) {
+            let region = match (attrs.get(&["lines"]), attrs.get(&["anchor"])) {
+                (Some(lines), _) => Region::parse_lines(lines)?,
+                (None, Some(anchor)) => Region::Anchor(anchor.to_owned()),
+                (None, None) => Region::Whole,
+            };
+            let code = read_included_code(&opts.base_dir, path, &region)?;
+            open_included_code_block(opts, &cap["lang"], path, &attrs, &code)
+        } else {
+            let mut spec = CodeSpec::from_attributes(&cap["lang"], &attrs)?;
+            spec.caption = spec.caption.map(|c| fragile_string_process(&c, opts));
+            Ok(open_code_block(opts, &spec))
+        }
+    } else if let Some(cap) = RE_CODE_FLOAT.captures(info) {
+        let lang = cap.name("lang").map_or("ERROR", |m| m.as_str().trim());
+        let label = cap.name("label").map_or("ERROR", |m| m.as_str().trim());
+        let caption = cap.name("caption").map_or("ERROR", |m| m.as_str().trim());
+        let spec = CodeSpec {
+            label: Some(label.to_owned()),
+            caption: Some(fragile_string_process(caption, opts)),
+            float: true,
+            ..CodeSpec::new(lang)
+        };
+        Ok(open_code_block(opts, &spec))
     } else {
-        // Don't do simple processing here because this is verbatim code.
-        // We do need to add a newline though
-        let code = format!("{}\n", line);
-        Ok((State::Code, code))
+        // Only the first word of the info string is the language
+        let lang = info.split_whitespace().next().unwrap_or("");
+        Ok(open_code_block(opts, &CodeSpec::new(lang)))
     }
 }
+
 fn process_line_figure(line: &str) -> Result<(State, String), Error> {
     if line.trim().is_empty() {
         Ok((State::FigureCaption, "\n\\caption{".to_owned()))
@@ -346,7 +1574,7 @@ fn process_line_figure(line: &str) -> Result<(State, String), Error> {
         Ok((State::Figure, fig))
     }
 }
-fn process_line_figure_caption(line: &str) -> Result<(State, String), Error> {
+fn process_line_figure_caption(line: &str, opts: &Options) -> Result<(State, String), Error> {
     if line.trim().is_empty() {
         Ok((State::Text, "}\n\\end{figure}\n\n".to_owned()))
     } else {
@@ -355,7 +1583,7 @@ fn process_line_figure_caption(line: &str) -> Result<(State, String), Error> {
             if line.trim().starts_with("\\label{") {
                 line.to_owned()
             } else {
-                simple_string_process(line)
+                fragile_string_process(line, opts)
             }
         );
         Ok((State::FigureCaption, caption))
@@ -377,7 +1605,32 @@ fn process_line_table_header(line: &str) -> Result<(State, String), Error> {
     }
 }
 
-fn process_line_table_body(line: &str, line_every_row: bool) -> Result<(State, String), Error> {
+/// Format a single cell of the table's header row.
+/// The label gets the same inline processing as body cells and is set in bold.
+/// A label split with `<br>` becomes a multi-line `\makecell` (package makecell),
+/// and labels over `siunitx` `S` columns are braced so they aren't parsed as numbers.
+fn table_header_cell(label: &str, desc: &str, opts: &Options) -> String {
+    let lines = RE_LINE_BREAK_TAG
+        .split(label)
+        .map(str::trim)
+        .map(|l| fragile_string_process(l, opts))
+        .collect::<Vec<_>>();
+    let mut cell = if lines.len() > 1 {
+        format!("\\textbf{{\\makecell{{{}}}}}", lines.join(" \\\\ "))
+    } else {
+        format!("\\textbf{{{}}}", lines.join(""))
+    };
+    if desc.starts_with('S') {
+        cell = format!("{{{}}}", cell);
+    }
+    cell
+}
+
+fn process_line_table_body(
+    line: &str,
+    line_every_row: bool,
+    opts: &Options,
+) -> Result<(State, String), Error> {
     let trimmed = line.trim();
     if trimmed.is_empty() {
         Ok((
@@ -390,7 +1643,7 @@ fn process_line_table_body(line: &str, line_every_row: bool) -> Result<(State, S
             trimmed[1..]
                 .split_terminator('|')
                 .map(str::trim)
-                .map(simple_string_process)
+                .map(|c| fragile_string_process(c, opts))
                 .collect::<Vec<_>>()
                 .join(" & ")
                 .as_str(),
@@ -399,7 +1652,7 @@ fn process_line_table_body(line: &str, line_every_row: bool) -> Result<(State, S
         Ok((State::TableBody(line_every_row), body))
     }
 }
-fn process_line_table_caption(line: &str) -> Result<(State, String), Error> {
+fn process_line_table_caption(line: &str, opts: &Options) -> Result<(State, String), Error> {
     if line.is_empty() {
         Ok((State::Text, "}\n\\end{table}\n\n".to_owned()))
     } else {
@@ -408,7 +1661,7 @@ fn process_line_table_caption(line: &str) -> Result<(State, String), Error> {
             if line.trim().starts_with("\\label{") {
                 line.to_owned()
             } else {
-                simple_string_process(line)
+                fragile_string_process(line, opts)
             }
         );
         Ok((State::TableCaption, caption))
@@ -421,32 +1674,270 @@ fn process_literal(line: &str) -> Result<(State, String), Error> {
         Ok((State::Literal, format!("{}\n", line)))
     }
 }
-fn process_footnote_body(line: &str) -> Result<(State, String), Error> {
-    if line.is_empty() {
-        Ok((State::Text, "}\n\n".to_owned()))
+fn process_footnote_body(
+    line: &str,
+    note: &FootnoteState,
+    opts: &Options,
+) -> Result<(State, String), Error> {
+    let trimmed = line.trim();
+    let indented = indent_width(line) >= 4;
+    // The body is the argument of \footnotetext
+    let body_opts = &opts.in_argument();
+    if trimmed.is_empty() && matches!(*note.inner, State::Term(_)) {
+        // The held back line was a paragraph of the body
+        let next = FootnoteState {
+            inner: Box::new(State::Text),
+            blank: true,
+        };
+        return Ok((State::FootnoteBody(next), note.inner.finish(body_opts)));
+    }
+    if !note.inner.is_idle() {
+        // Inside a block of the body, which takes blank lines and indented ones
+        if trimmed.is_empty() || indented {
+            let (inner, text) = note.inner.process_line(&strip_indent(line, 4), body_opts)?;
+            let next = FootnoteState {
+                inner: Box::new(inner),
+                blank: trimmed.is_empty(),
+            };
+            return Ok((State::FootnoteBody(next), text));
+        }
+        let next = FootnoteState {
+            inner: Box::new(State::Text),
+            blank: note.blank,
+        };
+        let (state, text) = process_footnote_body(line, &next, opts)?;
+        return Ok((state, note.inner.finish(body_opts) + &text));
+    }
+
+    if trimmed.is_empty() {
+        // Another paragraph of the body may follow, so don't close it yet
+        let next = FootnoteState {
+            inner: Box::new(State::Text),
+            blank: true,
+        };
+        Ok((State::FootnoteBody(next), String::new()))
+    } else if indented || !note.blank {
+        // More of the body; after a blank line it starts a new paragraph
+        let content = if indented {
+            strip_indent(line, 4)
+        } else {
+            trimmed.to_owned()
+        };
+        let (inner, text) = note.inner.process_line(&content, body_opts)?;
+        let next = FootnoteState {
+            inner: Box::new(inner),
+            blank: false,
+        };
+        let text = if note.blank {
+            "\n".to_owned() + &text
+        } else {
+            text
+        };
+        Ok((State::FootnoteBody(next), text))
     } else {
-        Ok((State::FootnoteBody, simple_string_process(line)))
+        // A line that isn't indented after a blank line ends the footnote
+        let (state, text) = process_line_text(line, opts)?;
+        Ok((state, "}\n\n".to_owned() + &text))
     }
 }
-fn process_unnumbered_equation_text(line: &str) -> Result<(State, String), Error> {
-    if line == "$$" {
-        Ok((State::Text, "\\end{equation*}".to_owned()))
+fn process_line_math(
+    line: &str,
+    block: &MathBlock,
+    opts: &Options,
+) -> Result<(State, String), Error> {
+    let close: &Regex = if block.bracketed {
+        &RE_BRACKET_MATH_CLOSE
     } else {
-        Ok((State::UnnumberedEquation, line.to_owned()))
+        &RE_MATH_CLOSE
+    };
+    let mut block = block.clone();
+    if line.trim().is_empty() {
+        // Display math can't hold a blank line, so it was never closed.
+        // Closing it here keeps the damage to one paragraph.
+        let delim = if block.bracketed { "\\[" } else { "$$" };
+        eprintln!(
+            "Display math opened with {} isn't closed before a blank line; closing it there.",
+            delim
+        );
+        let tex = block.end();
+        opts.math_macros.check(&tex);
+        Ok((State::Text, tex + "\n"))
+    } else if let Some(cap) = close.captures(line.trim()) {
+        // The closing delimiter may follow the last line of math,
+        // and may have attributes with the label, `$$ {#eq:x}`
+        block.line(&cap["math"]);
+        if let Some(attrs) = cap.name("attrs") {
+            let attrs = Attributes::parse(attrs.as_str())?;
+            block.label = attrs.id.or(block.label);
+        }
+        let tex = block.end();
+        opts.math_macros.check(&tex);
+        Ok((State::Text, tex))
+    } else {
+        // Nothing is written until the block closes
+        block.line(line);
+        Ok((State::Math(block), String::new()))
+    }
+}
+
+/// Display math on a single line, `$$ E = mc^2 $$ {#eq:einstein}` or `\[ E = mc^2 \]`.
+/// The attributes may name the environment as well as the label.
+fn single_line_math(math: &str, attrs: Option<&str>, opts: &Options) -> Result<String, Error> {
+    let attrs = attrs
+        .map(Attributes::parse)
+        .transpose()?
+        .unwrap_or_default();
+    let env = attrs.classes.first().map_or("", String::as_str);
+    let mut block = MathBlock::open(env, attrs.id.clone())?;
+    block.line(math.trim());
+    let tex = block.end();
+    opts.math_macros.check(&tex);
+    Ok(tex)
+}
+/// The line after a possible definition list term.
+/// If it's a definition, `: text`, the term is the label of a description item;
+/// otherwise the term was the first line of a paragraph after all.
+fn process_line_term(
+    line: &str,
+    term: &str,
+    in_description: bool,
+    opts: &Options,
+) -> Result<(State, String), Error> {
+    if let Some(cap) = RE_DEFINITION.captures(line.trim()) {
+        let begin = if in_description {
+            ""
+        } else {
+            "\\begin{description}\n"
+        };
+        let mut label = fragile_string_process(term.trim(), opts);
+        if label.contains(']') {
+            // A bracket would end the optional argument early
+            label = format!("{{{}}}", label);
+        }
+        Ok((
+            State::Description(false),
+            format!(
+                "{}\\item[{}] {}\n",
+                begin,
+                label,
+                simple_string_process(&cap["def"], opts)
+            ),
+        ))
+    } else {
+        let mut text = if in_description {
+            "\\end{description}\n\n".to_owned()
+        } else {
+            String::new()
+        };
+        let (state, term) = paragraph_line(term, opts);
+        text.push_str(&term);
+        let (state, more) = process_line_paragraph(line, state == State::LineBreak, opts)?;
+        Ok((state, text + &more))
+    }
+}
+
+fn process_line_description(
+    line: &str,
+    blank: bool,
+    opts: &Options,
+) -> Result<(State, String), Error> {
+    let trimmed = line.trim();
+    if trimmed.is_empty() {
+        // The list may go on with another term
+        Ok((State::Description(true), String::new()))
+    } else if let Some(cap) = RE_DEFINITION.captures(trimmed) {
+        // Another definition of the same term, as a new paragraph of the item
+        Ok((
+            State::Description(false),
+            format!("\n{}\n", simple_string_process(&cap["def"], opts)),
+        ))
+    } else if blank && indent_width(line) > 0 {
+        // Another paragraph of the definition, indented under it
+        Ok((
+            State::Description(false),
+            format!("\n{}\n", simple_string_process(trimmed, opts)),
+        ))
+    } else if blank {
+        // Either the next term or the end of the list
+        match process_line_text(line, opts)? {
+            (State::Term(term), _) => Ok((State::DescriptionTerm(term), String::new())),
+            (state, text) => Ok((state, "\\end{description}\n\n".to_owned() + &text)),
+        }
+    } else {
+        // Continuation of the definition
+        Ok((
+            State::Description(false),
+            simple_string_process(trimmed, opts) + "\n",
+        ))
     }
 }
-fn process_numbered_equation_text(line: &str) -> Result<(State, String), Error> {
-    if line == "$$" {
-        Ok((State::Text, "\\end{equation}".to_owned()))
+
+/// A line after a line of a paragraph, `line_break` if that ended with a hard line break.
+fn process_line_paragraph(
+    line: &str,
+    line_break: bool,
+    opts: &Options,
+) -> Result<(State, String), Error> {
+    let (state, text) = if indent_width(line) >= 4 {
+        // An indented line can't interrupt a paragraph, it just continues it
+        paragraph_line(line.trim_start(), opts)
+    } else if list_item(line.trim(), None)
+        .is_some_and(|item| matches!(item.kind, ListKind::Enumerate(_)) && item.start != 1)
+    {
+        // Nor can a list that doesn't start at 1, which is more likely a number
+        // that happens to start the line, as in `in\n1984. The year...`
+        paragraph_line(line.trim_start(), opts)
+    } else {
+        match process_line_text(line, opts)? {
+            // Only the first line of a paragraph can be a definition list's term
+            (State::Term(term), _) => paragraph_line(&term, opts),
+            res => return Ok(res),
+        }
+    };
+    if !line_break {
+        Ok((state, text))
+    } else if text.starts_with(['[', '*']) {
+        // `\\` would take these as its optional arguments
+        Ok((state, "\\\\{}\n".to_owned() + &text))
     } else {
-        Ok((State::NumberedEquation, line.to_owned()))
+        Ok((state, "\\\\\n".to_owned() + &text))
     }
 }
-fn process_line_text(line: &str) -> Result<(State, String), Error> {
+
+/// A line of a paragraph, and the state after it.
+/// A line ending with two spaces or a backslash has a hard line break after it,
+/// which is left to the next line since there's none at the end of a paragraph.
+fn paragraph_line(line: &str, opts: &Options) -> (State, String) {
+    let trimmed = line.trim_end();
+    let (text, state) = if line.ends_with("  ") {
+        (trimmed, State::LineBreak)
+    } else if let Some(text) = trimmed
+        .strip_suffix('\\')
+        .filter(|text| !text.ends_with('\\'))
+    {
+        (text, State::LineBreak)
+    } else {
+        (trimmed, State::Paragraph)
+    };
+    (
+        state,
+        format!("{}\n", simple_string_process(text.trim(), opts)),
+    )
+}
+
+fn process_line_text(line: &str, opts: &Options) -> Result<(State, String), Error> {
     let trimmed = line.trim();
     if trimmed.is_empty() {
         // A new paragraph
         Ok((State::Text, "\n".to_owned()))
+    } else if indent_width(line) >= 4 {
+        // An indented code block, which has no language
+        let (begin, mut block) = open_code_block(opts, &CodeSpec::new(""));
+        let code = block.line(&strip_indent(line, 4));
+        Ok((State::Code(block), begin + &code))
+    } else if RE_THEMATIC_BREAK.is_match(line) {
+        // Before lists and emphasis, which would take `* * *` or `***` for their own
+        Ok((State::Text, opts.thematic_break.tex().to_owned()))
     } else if trimmed.starts_with("# ") {
         // Line is a top-level heading; treat it as a comment
         // There should only be one top-level heading per markdown anyway
@@ -490,8 +1981,23 @@ fn process_line_text(line: &str) -> Result<(State, String), Error> {
         if !optional.is_empty() {
             text.push_str(&format!("[{}]", optional));
         }
-        text.push_str("\n");
+        text.push('\n');
         Ok((State::Figure, text))
+    } else if let Some(cap) = RE_CODE_INCLUDE.captures(trimmed) {
+        // A code listing pulled in from a file, with no closing fence
+        let path = &cap["path"];
+        let region = match cap.name("region").map(|m| m.as_str()) {
+            Some(r) if RE_LINE_RANGE.is_match(r) => Region::parse_lines(r)?,
+            Some(anchor) => Region::Anchor(anchor.to_owned()),
+            None => Region::Whole,
+        };
+        let attrs = match cap.name("attrs") {
+            Some(a) => Attributes::parse(a.as_str())?,
+            None => Attributes::default(),
+        };
+        let code = read_included_code(&opts.base_dir, path, &region)?;
+        let (begin, block) = open_included_code_block(opts, "", path, &attrs, &code)?;
+        Ok((State::Text, begin + block.end()))
     } else if trimmed == "|literal" {
         Ok((State::Literal, "".to_owned()))
     } else if trimmed.starts_with('|') {
@@ -501,7 +2007,9 @@ fn process_line_text(line: &str) -> Result<(State, String), Error> {
             bail!("Unexpected line ending for table.  The line starts with '|' but does not end with '|'.\n{}", line);
         }
         // The heading text and formatting strings are in the same line in markdown
-        let column_re_captures = trimmed[1..]
+        let column_re_captures = trimmed
+            .strip_prefix('|')
+            .unwrap()
             .split_terminator('|')
             .map(str::trim)
             .map(|h| RE_TABLE_HEADER.captures(h))
@@ -518,100 +2026,72 @@ fn process_line_text(line: &str) -> Result<(State, String), Error> {
             .iter()
             .map(|opt| opt.as_ref().unwrap())
             .collect::<Vec<_>>();
+        // If no column format is specified, default to centered
+        let descs = columns
+            .iter()
+            .map(|cap| cap.name("desc").map_or("c", |m| m.as_str().trim()))
+            .collect::<Vec<_>>();
 
         let mut table = String::new();
         table.push_str("\\begin{table}\n\\begin{tabular}{");
-        table.push_str(
-            columns
-                .iter()
-                // If no column format is specified, default to centered
-                .map(|cap| cap.name("desc").map_or("c", |m| m.as_str().trim()))
-                .collect::<Vec<_>>()
-                .join(" ")
-                .as_str(),
-        );
+        table.push_str(descs.join(" ").as_str());
         table.push_str("}\n\\toprule\n");
         table.push_str(
             columns
                 .iter()
+                .zip(descs.iter())
                 // Should really bail if I can't pull a column label, but for now,
                 // just inserting the word ERROR
-                .map(|cap| cap.name("label").map_or("ERROR", |m| m.as_str().trim()))
-                .map(|label| format!("\\textbf{{{}}}", label))
+                .map(|(cap, desc)| {
+                    table_header_cell(
+                        cap.name("label").map_or("ERROR", |m| m.as_str().trim()),
+                        desc,
+                        opts,
+                    )
+                })
                 .collect::<Vec<_>>()
                 .join(" & ")
                 .as_str(),
         );
         table.push_str(" \\\\\n");
         Ok((State::TableHeader, table))
-    } else if let Some(cap) = RE_CODE_FLOAT.captures(trimmed) {
-        let mut listing = "\\begin{lstlisting}".to_owned();
-        let lang = cap.name("lang").map_or("ERROR", |m| m.as_str().trim());
-        let label = cap.name("label").map_or("ERROR", |m| m.as_str().trim());
-        let caption = cap.name("caption").map_or("ERROR", |m| m.as_str().trim());
-        listing.push_str(&format!(
-            "[\n\tstyle={},\n\tlabel={},\n\tcaption={{{}}},\n\tfloat]",
-            lang, label, caption
-        ));
-        listing.push('\n');
-        Ok((State::Code, listing))
-    } else if let Some(cap) = RE_CODE_HERE.captures(trimmed) {
-        let lang = cap.name("lang").map_or("ERROR", |m| m.as_str().trim());
-        let mut listing = "\\begin{lstlisting}".to_owned();
-        if !lang.is_empty() {
-            listing.push_str(&format!("[style={}]", lang));
-        }
-        listing.push('\n');
-        Ok((State::Code, listing))
-    } else if line.starts_with('`') {
-        let mut text = RE_MONO_FONT
-            .replace_all(&line, |cap: &Captures| {
-                format!(r"\texttt{{{}}}", &cap["mono"])
-            })
-            .to_string();
-        text.push_str("\n");
-        Ok((State::Text, text))
-    } else if trimmed.starts_with("> ") {
-        // Start of a quote environment
-        // TODO: What about quotes that start with four spaces?
-        // We've already trimmed off the leading spaces!
-        // For now, we don't support that method for quoting
-        let mut quote = "\\begin{displayquote}\n".to_owned();
-        quote.push_str(&simple_string_process(&line[2..]));
-        quote.push('\n');
-        Ok((State::Quote, quote))
-    } else if let Some(cap) = RE_START_ITEMIZE.captures(trimmed) {
-        // Line starts with a '* ' or '+ ' or '- ', which is an itemized list.
-        let mut list = "\\begin{itemize}\n".to_owned();
-        list.push_str("\\item ");
-        list.push_str(&simple_string_process(&cap["item"]));
-        list.push('\n');
-        let indent = line.chars().take_while(|ch| ch.is_whitespace()).count();
-        if indent > u8::max_value() as usize {
-            Err(anyhow!(
-                "Leading indent cannot be more than {}, however I got {}.",
-                u8::max_value(),
-                indent
-            ))
-        } else {
-            Ok((State::Unordered(smallvec![indent as u8]), list))
-        }
-    } else if let Some(cap) = RE_START_ENUMERATE.captures(trimmed) {
-        // Line starts with a number and a period.  This is an enumerated list
-        let mut list = "\\begin{enumerate}\n".to_owned();
-        list.push_str("\\item ");
-        list.push_str(&simple_string_process(&cap["item"]));
-        list.push('\n');
-        let indent = line.chars().take_while(|ch| ch.is_whitespace()).count();
-        if indent > u8::max_value() as usize {
-            Err(anyhow!(
-                "Leading indent cannot be more than {}, however I got {}.",
-                u8::max_value(),
-                indent
-            ))
-        } else {
-            Ok((State::Ordered(smallvec![indent as u8]), list))
-        }
+    } else if let Some(cap) = RE_CODE_FENCE
+        .captures(line)
+        // The info string of a backtick fence can't have backticks, or it'd be inline code
+        .filter(|cap| !(cap["fence"].starts_with('`') && cap["info"].contains('`')))
+    {
+        let fence = Fence {
+            ch: cap["fence"].chars().next().unwrap(),
+            len: cap["fence"].len(),
+            indent: cap["indent"].len(),
+        };
+        let (begin, mut block) =
+            open_fenced_code_block(cap["info"].trim(), opts).unwrap_or_else(|err| {
+                eprintln!("{}", err);
+                (String::new(), CodeBlock::skipped())
+            });
+        block.fence = Some(fence);
+        Ok((State::Code(block), begin))
+    } else if let Some(cap) = RE_DIV_OPEN.captures(line) {
+        open_div(cap["fence"].len(), &cap["attrs"], opts)
+    } else if quote_content(line).is_some() {
+        // Start of a quote environment, whose content is markdown like any other
+        process_line_quote(line, &QuoteState::new(), opts)
+    } else if let Some(ListItem {
+        kind,
+        start,
+        text,
+        width,
+    }) = list_item(trimmed, None)
+    {
+        let indent = indent_width(line);
+        let list = format!("{}\n{}", kind.begin(start), list_item_text(&text, opts));
+        let level = ListLevel {
+            indent,
+            content: indent + width,
+            kind,
+        };
+        Ok((State::List(ListState::new(smallvec![level])), list))
     } else if let Some(cap) = RE_FOOTNOTE_BODY.captures(trimmed) {
         let mut body = "\\footnotetext[".to_owned();
         body.push_str(&cap["mark"]);
@@ -620,26 +2100,53 @@ fn process_line_text(line: &str) -> Result<(State, String), Error> {
             if let Some(link_cap) = RE_LINK.captures(&cap["body"]) {
                 format!(r"\url{{{}}}", &link_cap["link"])
             } else {
-                simple_string_process(&cap["body"])
+                fragile_string_process(&cap["body"], opts)
             }
             .as_str(),
         );
-        body.push_str("\n");
-        Ok((State::FootnoteBody, body))
-    } else if trimmed == "$$" {
-        Ok((State::UnnumberedEquation, "\\begin{equation*}\n".to_owned()))
+        body.push('\n');
+        let note = FootnoteState {
+            inner: Box::new(State::Paragraph),
+            blank: false,
+        };
+        Ok((State::FootnoteBody(note), body))
+    } else if let Some(cap) = RE_MATH_SINGLE
+        .captures(trimmed)
+        .or_else(|| RE_BRACKET_MATH_SINGLE.captures(trimmed))
+    {
+        let math = single_line_math(&cap["math"], cap.name("attrs").map(|m| m.as_str()), opts)?;
+        Ok((State::Text, math))
+    } else if let Some(cap) = RE_MATH_ATTRS.captures(trimmed) {
+        // Display math opened like `$$ {.align #eq:sys}`
+        let attrs = Attributes::parse(&cap["attrs"])?;
+        let env = attrs.classes.first().map_or("", String::as_str);
+        let block = MathBlock::open(env, attrs.id.clone())?;
+        Ok((State::Math(block), String::new()))
     } else if let Some(cap) = RE_NUM_EQUATION.captures(trimmed) {
-        let mut body = "\\begin{equation}\\label{".to_owned();
-        body.push_str(&cap["label"]);
-        body.push_str("}\n");
-        Ok((State::NumberedEquation, body))
-    } else if let Some(_) = RE_LINE_COMMENT.captures(trimmed) {
+        // Display math opened like `$$`, `$$<!--eq:x-->` or `$$align<!--eq:sys-->`
+        let label = cap.name("label").map(|m| m.as_str().to_owned());
+        let block = MathBlock::open(cap.name("env").map_or("", |m| m.as_str()), label)?;
+        Ok((State::Math(block), String::new()))
+    } else if RE_BRACKET_MATH_OPEN.is_match(trimmed) {
+        // Display math opened by `\[` on a line of its own
+        let mut block = MathBlock::open("", None)?;
+        block.bracketed = true;
+        Ok((State::Math(block), String::new()))
+    } else if let Some(cap) = RE_DIRECTIVE.captures(trimmed) {
+        let tex = match &cap["directive"] {
+            "appendix" => "\\appendix\n",
+            "clearpage" => "\\clearpage\n",
+            _ => "\\newpage\n",
+        };
+        Ok((State::Text, tex.to_owned()))
+    } else if RE_LINE_COMMENT.is_match(trimmed) {
         // If we have a line comment, and strip it out using simple string process,
         // we end up with a blank line in the latex, which signals a new paragraph.
         Ok((State::Text, String::new()))
     } else {
-        // Nothing special about this line, just regular ol' simple markdown
-        Ok((State::Text, format!("{}\n", simple_string_process(line))))
+        // Nothing special about this line, just regular ol' simple markdown.
+        // It's held back until we know it isn't a definition list's term.
+        Ok((State::Term(line.to_owned()), String::new()))
     }
 }
 
@@ -647,6 +2154,12 @@ fn process_line_text(line: &str) -> Result<(State, String), Error> {
 mod re_tests {
     /// For testing the regular expressions
     use super::*;
+    use std::io::BufRead;
+
+    /// Run a whole markdown document through the converter.
+    fn convert_str(md: &str, opts: Options) -> String {
+        convert(io::Cursor::new(md).lines(), opts).collect()
+    }
 
     #[test]
     fn test_all_headers() {
@@ -735,6 +2248,23 @@ mod re_tests {
         assert_eq!(desc, expected_desc);
     }
 
+    #[test]
+    fn test_table_header_cells() {
+        let header = "| *Name* & `id` | <!--S--> Rate (%)[^1] | <!--l--> First<br>Second |";
+        let expected = concat!(
+            "\\begin{table}\n\\begin{tabular}{c S l}\n\\toprule\n",
+            "\\textbf{\\textbf{Name} \\& \\texttt{id}} & ",
+            "{\\textbf{Rate (\\%)\\footnotemark[1]}} & ",
+            "\\textbf{\\makecell{First \\\\ Second}} \\\\\n"
+        );
+
+        let r_processed = process_line_text(header, &Options::default());
+        assert!(r_processed.is_ok());
+        let (state, table) = r_processed.ok().unwrap();
+        assert!(state == State::TableHeader);
+        assert_eq!(table, expected);
+    }
+
     #[test]
     fn test_footnote_mark() {
         let footnote_mark = "asdf";
@@ -752,7 +2282,7 @@ mod re_tests {
         let mark = o_mark.unwrap();
         assert!(mark.as_str() == footnote_mark);
 
-        let processed = simple_string_process(&test_str);
+        let processed = simple_string_process(&test_str, &Options::default());
         assert!(processed == expected_text);
     }
 
@@ -777,10 +2307,10 @@ mod re_tests {
         let body = o_body.unwrap();
         assert!(body.as_str() == footnote_body);
 
-        let r_processed = process_line_text(&test_str);
+        let r_processed = process_line_text(&test_str, &Options::default());
         assert!(r_processed.is_ok());
         let processed = r_processed.ok().unwrap();
-        assert!(processed.0 == State::FootnoteBody);
+        assert!(matches!(processed.0, State::FootnoteBody(_)));
         assert!(processed.1 == expected_text);
     }
 
@@ -797,7 +2327,7 @@ mod re_tests {
             "  \t  <!-- This is a comment and is expected to be removed. -->  \t\n",
             "  \t  <!-- This is a comment and is expected to be removed. -->  \t  \n",
         ] {
-            let processed = simple_string_process(&test_str);
+            let processed = simple_string_process(test_str, &Options::default());
             assert!(processed.trim().is_empty());
         }
 
@@ -815,7 +2345,7 @@ mod re_tests {
         ] {
             let test_str = prefix.to_owned() + comment_text + postfix;
             let expected_str = prefix.to_owned() + postfix;
-            let processed = simple_string_process(&test_str);
+            let processed = simple_string_process(&test_str, &Options::default());
             assert!(processed == expected_str);
         }
     }
@@ -841,7 +2371,7 @@ mod re_tests {
         assert!(o_path.is_some());
         assert!(o_path.unwrap().as_str() == raw_page_path);
 
-        let processed = process_line_text(&page_link);
+        let processed = process_line_text(&page_link, &Options::default());
         assert!(processed.is_ok());
         let (state, import) = processed.ok().unwrap();
         assert!(state == State::Text);
@@ -851,7 +2381,7 @@ mod re_tests {
     #[test]
     fn test_equations() {
         let eqn_line = r#"$$<!--eq:test-->"#;
-        let o_cap = RE_NUM_EQUATION.captures(&eqn_line);
+        let o_cap = RE_NUM_EQUATION.captures(eqn_line);
         assert!(o_cap.is_some());
         let cap = o_cap.unwrap();
         let o_label = cap.name("label");
@@ -863,7 +2393,7 @@ mod re_tests {
     #[test]
     fn test_code_regex() {
         let code_line = r#"```python<!--lst:test--><!--Hello World, this is a caption!-->"#;
-        let o_cap = RE_CODE_FLOAT.captures(&code_line);
+        let o_cap = RE_CODE_FLOAT.captures(code_line);
         assert!(o_cap.is_some());
         let cap = o_cap.unwrap();
 
@@ -886,6 +2416,549 @@ mod re_tests {
         assert!(caption_text.as_str() == "Hello World, this is a caption!");
     }
 
+    #[test]
+    fn test_commonmark_code_blocks() {
+        let md = concat!(
+            "  ~~~~ python   \n",
+            "  print('```')\n",
+            "~~~\n",
+            "```\n",
+            "  ~~~~~\n",
+            "Text before\n",
+            "    still text\n",
+            "\n",
+            "    indented code\n",
+            "\n",
+            "\t  tabbed\n",
+            "\n",
+            "After\n",
+            "````\n",
+            "unclosed\n",
+        );
+        let expected = concat!(
+            "\\begin{lstlisting}[language=Python]\n",
+            "print('```')\n",
+            "~~~\n",
+            "```\n",
+            "\\end{lstlisting}\n",
+            "Text before\n",
+            "still text\n",
+            "\n",
+            "\\begin{lstlisting}\n",
+            "indented code\n",
+            "\n",
+            "  tabbed\n",
+            "\\end{lstlisting}\n",
+            "\n",
+            "After\n",
+            "\\begin{lstlisting}\n",
+            "unclosed\n",
+            "\\end{lstlisting}\n",
+        );
+        assert_eq!(convert_str(md, Options::default()), expected);
+
+        // A block that can't be opened still runs to its closing fence
+        let md = "```rust {include=missing.rs}\n```\n\nAfter\n";
+        assert_eq!(convert_str(md, Options::default()), "\nAfter\n");
+    }
+
+    #[test]
+    fn test_links() {
+        let opts = Options::default();
+        assert_eq!(
+            simple_string_process(
+                "[Docs](https://e.com/a%20b?x=1&y=snake_case) for 5% more",
+                &opts
+            ),
+            "Docs \\url{https://e.com/a%20b?x=1&y=snake_case} for 5\\% more"
+        );
+        // In an argument `%` would start a comment, and hyperref's \url takes `\%` for it
+        assert_eq!(
+            fragile_string_process("[Docs](https://e.com/a%20b)", &opts),
+            "Docs \\url{https://e.com/a\\%20b}"
+        );
+        assert!(preamble(&opts).contains("\\usepackage{hyperref}\n\n\\begin{document}"));
+    }
+
+    #[test]
+    fn test_verbatim_safe_inline_code() {
+        let opts = Options::default();
+        assert_eq!(
+            simple_string_process("Use `a_b%c{}` and ``x`*y*`` for *z*", &opts),
+            "Use \\lstinline|a_b%c{}| and \\lstinline|x`*y*| for \\textbf{z}"
+        );
+        assert_eq!(
+            simple_string_process("`x = 1`{.python} and a lone ` tick", &opts),
+            "\\lstinline[language=Python]|x = 1| and a lone ` tick"
+        );
+        assert_eq!(
+            fragile_string_process("`a_b%c{}` & 'q'", &opts),
+            "\\texttt{a\\_b\\%c\\{\\}} \\& `q'"
+        );
+    }
+
+    #[test]
+    fn test_mixed_nested_lists() {
+        let md = concat!(
+            "1. step\n",
+            "   - bullet\n",
+            "     1. deeper\n",
+            "   - bullet two\n",
+            "2. second\n",
+            "- switched\n",
+        );
+        let expected = concat!(
+            "\\begin{enumerate}\n\\item step\n",
+            "\\begin{itemize}\n\\item bullet\n",
+            "\\begin{enumerate}\n\\item deeper\n",
+            "\\end{enumerate}\n\\item bullet two\n",
+            "\\end{itemize}\n\\item second\n",
+            "\\end{enumerate}\n\\begin{itemize}\n\\item switched\n",
+            "\\end{itemize}\n",
+        );
+        assert_eq!(convert_str(md, Options::default()), expected);
+
+        // An item less indented than the first is still one of the outermost list
+        let md = "  - a\n    - nested\n- b\n";
+        let expected = concat!(
+            "\\begin{itemize}\n\\item a\n",
+            "\\begin{itemize}\n\\item nested\n\\end{itemize}\n",
+            "\\item b\n\\end{itemize}\n",
+        );
+        assert_eq!(convert_str(md, Options::default()), expected);
+    }
+
+    #[test]
+    fn test_list_numbering() {
+        let md = concat!(
+            "5. five\n",
+            "   a) alpha\n",
+            "   b) beta\n",
+            "6. six\n",
+            "\n",
+            "h. eight\n",
+            "i. nine\n",
+            "\n",
+            "iv. four\n",
+            "\n",
+            "A.  Capital\n",
+            "\n",
+            "B. Russell wrote.\n",
+        );
+        let expected = concat!(
+            "\\begin{enumerate}[start=5]\n\\item five\n",
+            "\\begin{enumerate}[label=\\alph*)]\n\\item alpha\n\\item beta\n",
+            "\\end{enumerate}\n\\item six\n",
+            "\\end{enumerate}\n\\begin{enumerate}[label=\\alph*., start=8]\n",
+            "\\item eight\n\\item nine\n",
+            "\\end{enumerate}\n\\begin{enumerate}[label=\\roman*., start=4]\n\\item four\n",
+            "\\end{enumerate}\n\\begin{enumerate}[label=\\Alph*.]\n\\item Capital\n",
+            "\\end{enumerate}\n\nB. Russell wrote.\n",
+        );
+        assert_eq!(convert_str(md, Options::default()), expected);
+
+        // Words and headings that look like markers, and numbers starting a line
+        // in a paragraph, aren't lists
+        let md = concat!(
+            "civil. war\n",
+            "\n",
+            "I. Introduction\n",
+            "\n",
+            "II.  Method\n",
+            "\n",
+            "In the year\n",
+            "1984. The end.\n",
+            "\n",
+            "Steps\n",
+            "1. one\n",
+        );
+        let expected = concat!(
+            "civil. war\n\nI. Introduction\n\n",
+            "\\begin{enumerate}[label=\\Roman*., start=2]\n\\item Method\n",
+            "\\end{enumerate}\n\nIn the year\n1984. The end.\n\nSteps\n",
+            "\\begin{enumerate}\n\\item one\n\\end{enumerate}\n",
+        );
+        assert_eq!(convert_str(md, Options::default()), expected);
+    }
+
+    #[test]
+    fn test_task_lists() {
+        let md = "- [ ] todo\n- [x] done\n  1. [X] nested\n";
+        let expected = concat!(
+            "\\begin{itemize}\n\\item[$\\square$] todo\n\\item[$\\boxtimes$] done\n",
+            "\\begin{enumerate}\n\\item[$\\boxtimes$] nested\n",
+            "\\end{enumerate}\n\\end{itemize}\n",
+        );
+        assert_eq!(convert_str(md, Options::default()), expected);
+
+        let opts = Options {
+            task_checked: Some("\\checkmark".to_owned()),
+            ..Options::default()
+        };
+        assert!(convert_str(md, opts).contains("\\item[\\checkmark] done\n"));
+    }
+
+    #[test]
+    fn test_definition_lists() {
+        let md = concat!(
+            "Apple\n",
+            ": A *red* fruit\n",
+            ": A company\n",
+            "\n",
+            "Banana\n",
+            ": Yellow\n",
+            "\n",
+            "    and curved.\n",
+            "\n",
+            "Not a term\n",
+            "but a paragraph\n",
+        );
+        let expected = concat!(
+            "\\begin{description}\n\\item[Apple] A \\textbf{red} fruit\n",
+            "\nA company\n",
+            "\\item[Banana] Yellow\n",
+            "\nand curved.\n",
+            "\\end{description}\n\nNot a term\nbut a paragraph\n",
+        );
+        assert_eq!(convert_str(md, Options::default()), expected);
+
+        // A line after a blank that isn't a term, at the end, is a paragraph of its own
+        assert_eq!(
+            convert_str("Kiwi\n: Green\n\nLast line  \n", Options::default()),
+            "\\begin{description}\n\\item[Kiwi] Green\n\\end{description}\n\nLast line\n"
+        );
+    }
+
+    #[test]
+    fn test_nested_quotes() {
+        let md = concat!(
+            "> outer\n",
+            ">> nested\n",
+            ">\n",
+            "> - item\n",
+            ">\n",
+            "> -- Author, _Work_\n",
+            "\n",
+            "> text\n",
+            "> -- not the last line\n",
+            "> end\n",
+        );
+        let expected = concat!(
+            "\\begin{displayquote}[Author, \\emph{Work}]\nouter\n",
+            "\\begin{displayquote}\nnested\n\\end{displayquote}\n\n",
+            "\\begin{itemize}\n\\item item\n\\end{itemize}\n",
+            "\\end{displayquote}\n\n",
+            "\\begin{displayquote}\ntext\n-- not the last line\nend\n",
+            "\\end{displayquote}\n",
+        );
+        assert_eq!(convert_str(md, Options::default()), expected);
+    }
+
+    #[test]
+    fn test_callouts() {
+        let md = "> [!WARNING]\n> Careful.\n\n> [!TIP] Pro tip\n> Short.\n";
+        let expected = concat!(
+            "\\begin{calloutwarning}\nCareful.\n\\end{calloutwarning}\n\n",
+            "\\begin{callouttip}[Pro tip]\nShort.\n\\end{callouttip}\n",
+        );
+        assert_eq!(convert_str(md, Options::default()), expected);
+    }
+
+    #[test]
+    fn test_fenced_divs() {
+        let md = concat!(
+            ":::: {.theorem #thm:main title=\"Fermat\" year=1637}\n",
+            "No *three* integers.\n",
+            "\n",
+            "::: proof\n",
+            "```\n",
+            ":::\n",
+            "```\n",
+            ":::\n",
+            "::::\n",
+        );
+        let expected = concat!(
+            "\\begin{theorem}[Fermat]\\label{thm:main}\n",
+            "No \\textbf{three} integers.\n",
+            "\n",
+            "\\begin{proof}\n\\begin{lstlisting}\n:::\n\\end{lstlisting}\n",
+            "\\end{proof}\n\\end{theorem}\n",
+        );
+        assert_eq!(convert_str(md, Options::default()), expected);
+    }
+
+    #[test]
+    fn test_inline_footnotes() {
+        let opts = Options::default();
+        assert_eq!(
+            simple_string_process("Text^[A *bold* note, [brackets] and `a[0]`.] and x^2^", &opts),
+            "Text\\footnote{A \\textbf{bold} note, [brackets] and \\texttt{a[0]}.} and x\\textsuperscript{2}"
+        );
+        assert_eq!(
+            simple_string_process("a^[outer ^[inner]] b ^[unclosed", &opts),
+            "a\\footnote{outer \\footnote{inner}} b ^[unclosed"
+        );
+    }
+
+    #[test]
+    fn test_footnote_mode_inline() {
+        let md = concat!(
+            "See[^note] and[^1].\n",
+            "\n",
+            "[^note]: A *named* note\n",
+            "\n",
+            "    with a second paragraph.\n",
+            "[^1]: Numbered.\n",
+        );
+        let opts = Options {
+            footnote_mode: FootnoteMode::Inline,
+            ..Options::default()
+        };
+        let expected = concat!(
+            "See\\footnote{A \\textbf{named} note\n\nwith a second paragraph.}",
+            " and\\footnote{Numbered.}.\n\n",
+        );
+        assert_eq!(convert_str(md, opts), expected);
+
+        let md = "Ref[^n].\n\n[^n]: Body `a_b`.\n";
+        let opts = Options {
+            footnote_mode: FootnoteMode::Inline,
+            ..Options::default()
+        };
+        assert_eq!(
+            convert_str(md, opts),
+            "Ref\\footnote{Body \\texttt{a\\_b}.}.\n\n"
+        );
+    }
+
+    #[test]
+    fn test_footnote_blocks() {
+        let md = concat!(
+            "[^1]: First line\n",
+            "second line.\n",
+            "\n",
+            "    Another paragraph.\n",
+            "\n",
+            "    - item\n",
+            "\n",
+            "After.\n",
+        );
+        let expected = concat!(
+            "\\footnotetext[1]{\nFirst line\nsecond line.\n",
+            "\nAnother paragraph.\n",
+            "\n\\begin{itemize}\n\\item item\n\\end{itemize}\n",
+            "}\n\nAfter.\n",
+        );
+        assert_eq!(convert_str(md, Options::default()), expected);
+
+        // The body is a command's argument, so code can't be verbatim there
+        let md = concat!(
+            "[^1]: First `a_b`\n",
+            "then `c_d`.\n",
+            "\n",
+            "    ```\n",
+            "    fn f() {\n",
+            "        a_b\n",
+            "    }\n",
+            "    ```\n",
+            "\n",
+            "After `e_f`.\n",
+        );
+        let expected = concat!(
+            "\\footnotetext[1]{\nFirst \\texttt{a\\_b}\nthen \\texttt{c\\_d}.\n",
+            "\n\\par\\noindent\n\\texttt{fn~f()~\\{}\n\\\\\\texttt{~~~~a\\_b}\n",
+            "\\\\\\texttt{\\}}\n\\par\n",
+            "}\n\nAfter \\lstinline|e_f|.\n",
+        );
+        assert_eq!(convert_str(md, Options::default()), expected);
+    }
+
+    #[test]
+    fn test_front_matter() {
+        let lines = ["---", "macros:", "  R: \\mathbb{R}", "---", "Text."].map(str::to_owned);
+        let (front_matter, rest) = split_front_matter(lines.to_vec());
+        assert_eq!(front_matter, vec!["macros:", "  R: \\mathbb{R}"]);
+        assert_eq!(rest, vec!["Text."]);
+
+        // A break that isn't followed by more front matter is left alone
+        let lines = ["---", "", "Text."].map(str::to_owned);
+        assert_eq!(split_front_matter(lines.to_vec()).1, lines.to_vec());
+        let lines = ["---", "Text."].map(str::to_owned);
+        assert!(split_front_matter(lines.to_vec()).0.is_empty());
+        // Nor is text between two breaks, or YAML without any key read here
+        let lines = ["---", "Some text: more", "---", "Text."].map(str::to_owned);
+        assert_eq!(split_front_matter(lines.to_vec()).1, lines.to_vec());
+        let lines = ["---", "A *paragraph*", "[link](x)", "---"].map(str::to_owned);
+        assert_eq!(split_front_matter(lines.to_vec()).1, lines.to_vec());
+    }
+
+    #[test]
+    fn test_breaks() {
+        let md = concat!(
+            "One  \n",
+            "two\\\n",
+            "[three]\n",
+            "end  \n",
+            "\n",
+            "- a\n",
+            "* * *\n",
+            "<!-- pagebreak -->\n",
+            "___\n",
+        );
+        let expected = concat!(
+            "One\n\\\\\ntwo\n\\\\{}\n[three]\nend\n\n",
+            "\\begin{itemize}\n\\item a\n\\end{itemize}\n",
+            "\\par\\medskip\\hrule\\medskip\n",
+            "\\newpage\n",
+            "\\par\\medskip\\hrule\\medskip\n",
+        );
+        assert_eq!(convert_str(md, Options::default()), expected);
+
+        let opts = Options {
+            thematic_break: ThematicBreak::Clearpage,
+            ..Options::default()
+        };
+        assert_eq!(
+            convert_str("Text.\n---\n<!-- appendix -->\n", opts),
+            "Text.\n\\clearpage\n\\appendix\n"
+        );
+    }
+
+    #[test]
+    fn test_flavors() {
+        let md = "Some **strong**, *emphasis*, snake_case and ~~gone~~.\n";
+        // The legacy dialect reads single asterisks as bold, as it always has
+        assert_eq!(
+            convert_str(md, Options::default()),
+            "Some \\textbf{*strong}\\textbf{, }emphasis*, snake_case and ~~gone~~.\n"
+        );
+        let opts = Options {
+            flavor: Flavor::Commonmark,
+            ..Options::default()
+        };
+        assert_eq!(
+            convert_str(md, opts),
+            concat!(
+                "Some \\textbf{strong}, \\emph{emphasis}, snake\\_case and ",
+                "\\textasciitilde{}\\textasciitilde{}gone\\textasciitilde{}\\textasciitilde{}.\n"
+            )
+        );
+        let opts = Options {
+            flavor: Flavor::Gfm,
+            ..Options::default()
+        };
+        assert_eq!(
+            convert_str(md, opts.clone()),
+            "Some \\textbf{strong}, \\emph{emphasis}, snake\\_case and \\sout{gone}.\n"
+        );
+        assert!(preamble(&opts).contains("\\usepackage[normalem]{ulem}\n"));
+        let opts = Options {
+            flavor: Flavor::Pandoc,
+            ..Options::default()
+        };
+        assert_eq!(
+            convert_str("H~2~O, ~~gone~~ and _$x_1 + y_{i}$_\n", opts),
+            "H\\textsubscript{2}O, \\sout{gone} and \\emph{$x_1 + y_{i}$}\n"
+        );
+    }
+
+    #[test]
+    fn test_display_math_forms() {
+        let md = concat!(
+            "$$ E = mc^2 $$ {#eq:einstein}\n",
+            "\\[ x \\]\n",
+            "$$\n",
+            "z = 3\n",
+            "$$ {#eq:z}\n",
+            "\\[\n",
+            "y = 2 \\]\n",
+            "after\n",
+        );
+        let expected = concat!(
+            "\\begin{equation}\\label{eq:einstein}\nE = mc^2\n\\end{equation}\n",
+            "\\begin{equation*}\nx\n\\end{equation*}\n",
+            "\\begin{equation}\\label{eq:z}\nz = 3\n\\end{equation}\n",
+            "\\begin{equation*}\ny = 2\n\\end{equation*}\n",
+            "after\n",
+        );
+        assert_eq!(convert_str(md, Options::default()), expected);
+
+        // Math only opens when the rest of the line is math or nothing
+        let md = "$$ a $$ and b\n\n\\[x\\] is a point\n\nEnd\n";
+        assert_eq!(
+            convert_str(md, Options::default()),
+            "$$ a $$ and b\n\n\\[x\\] is a point\n\nEnd\n"
+        );
+        // An unclosed block ends at the blank line
+        let md = "$$\nx = 1\n\nText\n";
+        assert_eq!(
+            convert_str(md, Options::default()),
+            "\\begin{equation*}\nx = 1\n\\end{equation*}\n\nText\n"
+        );
+    }
+
+    #[test]
+    fn test_loose_lists() {
+        let md = concat!(
+            "1. first\n",
+            "\n",
+            "   more of first\n",
+            "\n",
+            "   ```\n",
+            "   code\n",
+            "\n",
+            "   ```\n",
+            "2. second\n",
+            "   > quoted\n",
+            "\n",
+            "after\n",
+        );
+        let expected = concat!(
+            "\\begin{enumerate}\n\\item first\n",
+            "\nmore of first\n",
+            "\n\\begin{lstlisting}\ncode\n\n\\end{lstlisting}\n",
+            "\\item second\n",
+            "\\begin{displayquote}\nquoted\n\\end{displayquote}\n\n",
+            "\\end{enumerate}\n\nafter\n",
+        );
+        assert_eq!(convert_str(md, Options::default()), expected);
+    }
+
+    #[test]
+    fn test_list_depth() {
+        // Tabs advance to the next multiple of four, so "  \t" and "    " line up
+        let md = "- a\n  \t- b\n    - c\n\t\t- d\n\t\t\t- e\n\t\t\t\t- f\n";
+        let result = convert_str(md, Options::default());
+        assert!(result.contains("\\item b\n\\item c\n"));
+        // The fifth itemize is refused, so f stays in the fourth
+        assert!(result.contains("\\item e\n\\item f\n"));
+        assert_eq!(result.matches("\\begin{itemize}").count(), 4);
+        assert_eq!(result.matches("\\end{itemize}").count(), 4);
+
+        let opts = Options {
+            list_depth: Some(8),
+            ..Options::default()
+        };
+        let result = convert_str(md, opts.clone());
+        assert!(result.contains("\\item f"));
+        assert_eq!(result.matches("\\begin{itemize}").count(), 5);
+        assert_eq!(result.matches("\\end{itemize}").count(), 5);
+        assert!(preamble(&opts).contains("\\renewlist{itemize}{itemize}{8}"));
+
+        // LaTeX already allows four levels, so a lower limit isn't one
+        let opts = Options {
+            list_depth: Some(2),
+            ..Options::default()
+        };
+        assert!(preamble(&opts).contains("\\renewlist{itemize}{itemize}{4}"));
+        assert_eq!(
+            convert_str("- a\n  - b\n    - c\n", opts)
+                .matches("\\begin{itemize}")
+                .count(),
+            3
+        );
+    }
+
     #[test]
     fn test_escaped_underscore() {
         let is_emph = "This text _is_ emph!";
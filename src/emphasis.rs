@@ -0,0 +1,196 @@
+/// A run of `*` or `_` characters, which may open or close emphasis.
+struct Delimiter {
+    ch: char,
+    /// The characters not yet used for emphasis
+    count: usize,
+    /// The length of the run as written, for the rule of three
+    len: usize,
+    can_open: bool,
+    can_close: bool,
+    /// The markup this run opens, after its remaining characters, outermost first
+    opens: Vec<&'static str>,
+    /// How many emphases this run closes, before its remaining characters
+    closes: usize,
+}
+
+enum Piece {
+    Text(String),
+    /// The index of a delimiter run
+    Delimiter(usize),
+}
+
+/// Apply CommonMark emphasis to a line: `*` and `_` for `\emph`, doubled for `\textbf`.
+/// Delimiter runs are matched as the CommonMark spec lays out, so that `_` inside
+/// a word, as in `snake_case`, isn't emphasis, `***` gives both, and unmatched
+/// delimiters are left as they are, escaped in the case of `_`.
+pub fn emphasis(line: &str) -> String {
+    let (pieces, mut delims) = split(line);
+
+    // The runs that may still open or close emphasis, in order
+    let mut stack: Vec<usize> = (0..delims.len()).collect();
+    let mut current = 0;
+    while current < stack.len() {
+        let closer = stack[current];
+        if !delims[closer].can_close {
+            current += 1;
+            continue;
+        }
+        let opener = (0..current).rev().find(|&pos| {
+            let (o, c) = (&delims[stack[pos]], &delims[closer]);
+            // The rule of three: a run that may both open and close can't be matched
+            // with one whose lengths add to a multiple of 3, unless both are
+            let both = o.can_close || c.can_open;
+            o.ch == c.ch
+                && o.can_open
+                && !(both && (o.len + c.len) % 3 == 0 && (o.len % 3 != 0 || c.len % 3 != 0))
+        });
+        match opener {
+            Some(pos) => {
+                let open = stack[pos];
+                let n = if delims[open].count >= 2 && delims[closer].count >= 2 {
+                    2
+                } else {
+                    1
+                };
+                let markup = if n == 2 { "\\textbf{" } else { "\\emph{" };
+                delims[open].count -= n;
+                delims[open].opens.insert(0, markup);
+                delims[closer].count -= n;
+                delims[closer].closes += 1;
+                // Runs between the two can no longer match anything
+                stack.drain(pos + 1..current);
+                current = pos + 1;
+                if delims[open].count == 0 {
+                    stack.remove(pos);
+                    current -= 1;
+                }
+                if delims[closer].count == 0 {
+                    stack.remove(current);
+                }
+            }
+            None if !delims[closer].can_open => {
+                stack.remove(current);
+            }
+            None => current += 1,
+        }
+    }
+
+    let mut res = String::with_capacity(line.len());
+    for piece in pieces {
+        match piece {
+            Piece::Text(text) => res.push_str(&text),
+            Piece::Delimiter(i) => {
+                let delim = &delims[i];
+                res.extend(std::iter::repeat_n('}', delim.closes));
+                // A `_` that isn't emphasis is an underscore, which tex wants escaped
+                let leftover = if delim.ch == '_' { "\\_" } else { "*" };
+                res.extend(std::iter::repeat_n(leftover, delim.count));
+                res.extend(delim.opens.iter().copied());
+            }
+        }
+    }
+    res
+}
+
+/// Split a line into text and delimiter runs, working out which runs may open
+/// and close emphasis from the characters either side of them.
+fn split(line: &str) -> (Vec<Piece>, Vec<Delimiter>) {
+    let chars: Vec<char> = line.chars().collect();
+    let mut pieces = Vec::new();
+    let mut delims = Vec::new();
+    let mut text = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let ch = chars[i];
+        if ch == '\\' && i + 1 < chars.len() {
+            // An escaped character is never a delimiter.  `\*` is just an asterisk,
+            // while `\_` is already what LaTeX wants.
+            if chars[i + 1] != '*' {
+                text.push(ch);
+            }
+            text.push(chars[i + 1]);
+            i += 2;
+            continue;
+        }
+        if ch != '*' && ch != '_' {
+            text.push(ch);
+            i += 1;
+            continue;
+        }
+
+        let start = i;
+        while i < chars.len() && chars[i] == ch {
+            i += 1;
+        }
+        let before = start.checked_sub(1).map(|b| chars[b]);
+        let after = chars.get(i).copied();
+        let left_flanking = !is_space(after)
+            && (!is_punctuation(after) || is_space(before) || is_punctuation(before));
+        let right_flanking = !is_space(before)
+            && (!is_punctuation(before) || is_space(after) || is_punctuation(after));
+        let (can_open, can_close) = if ch == '*' {
+            (left_flanking, right_flanking)
+        } else {
+            // `_` can't open or close inside a word
+            (
+                left_flanking && (!right_flanking || is_punctuation(before)),
+                right_flanking && (!left_flanking || is_punctuation(after)),
+            )
+        };
+
+        if !text.is_empty() {
+            pieces.push(Piece::Text(std::mem::take(&mut text)));
+        }
+        pieces.push(Piece::Delimiter(delims.len()));
+        delims.push(Delimiter {
+            ch,
+            count: i - start,
+            len: i - start,
+            can_open,
+            can_close,
+            opens: Vec::new(),
+            closes: 0,
+        });
+    }
+    if !text.is_empty() {
+        pieces.push(Piece::Text(text));
+    }
+    (pieces, delims)
+}
+
+/// Whitespace, or the start or end of the line
+fn is_space(ch: Option<char>) -> bool {
+    ch.is_none_or(char::is_whitespace)
+}
+
+/// Punctuation or a symbol, which CommonMark treats alike
+fn is_punctuation(ch: Option<char>) -> bool {
+    ch.is_some_and(|ch| {
+        ch.is_ascii_punctuation()
+            || (!ch.is_ascii() && !ch.is_alphanumeric() && !ch.is_whitespace())
+    })
+}
+
+#[cfg(test)]
+mod emphasis_tests {
+    use super::*;
+
+    #[test]
+    fn test_emphasis() {
+        assert_eq!(emphasis("*foo bar*"), "\\emph{foo bar}");
+        assert_eq!(emphasis("_foo bar_"), "\\emph{foo bar}");
+        assert_eq!(emphasis("**foo bar**"), "\\textbf{foo bar}");
+        assert_eq!(emphasis("__foo__"), "\\textbf{foo}");
+        assert_eq!(emphasis("***both***"), "\\emph{\\textbf{both}}");
+        assert_eq!(emphasis("a * foo bar*"), "a * foo bar*");
+        assert_eq!(emphasis("foo*bar*"), "foo\\emph{bar}");
+        assert_eq!(emphasis("snake_case_name"), "snake\\_case\\_name");
+        assert_eq!(emphasis("__foo__bar"), "\\_\\_foo\\_\\_bar");
+        assert_eq!(emphasis("*foo**bar**baz*"), "\\emph{foo\\textbf{bar}baz}");
+        assert_eq!(emphasis("*(*foo*)*"), "\\emph{(\\emph{foo})}");
+        assert_eq!(emphasis("**foo*"), "*\\emph{foo}");
+        assert_eq!(emphasis("*foo**"), "\\emph{foo}*");
+        assert_eq!(emphasis("\\*not\\*"), "*not*");
+        assert_eq!(emphasis("*a \\_b\\_*"), "\\emph{a \\_b\\_}");
+    }
+}
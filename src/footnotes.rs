@@ -0,0 +1,189 @@
+use crate::code::Fence;
+use crate::converter::{indent_width, strip_indent};
+use clap::ValueEnum;
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+
+lazy_static! {
+    static ref RE_FOOTNOTE_DEF: Regex =
+        Regex::new(r#"^\[\^(?<mark>[^\]]+)]:?\s*(?<body>.*)$"#).unwrap();
+    static ref RE_FENCE: Regex = Regex::new(r#"^(?<indent> {0,3})(?<fence>`{3,}|~{3,})"#).unwrap();
+}
+
+/// How footnotes are written out.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum FootnoteMode {
+    /// `\footnotemark` at the reference and `\footnotetext` where the footnote is
+    /// defined.  The marks must be numbers.
+    #[default]
+    Marks,
+    /// Footnote definitions are collected from the whole file first, and each
+    /// reference becomes a `\footnote` holding its definition.  Marks may be names.
+    Inline,
+}
+
+/// The footnote definitions of a file, collected ahead of converting it.
+#[derive(Debug, Default)]
+pub struct Footnotes {
+    /// The markdown of each definition, without its mark or indentation
+    defs: HashMap<String, Vec<String>>,
+    /// The marks in the order they're defined, for reporting
+    order: Vec<String>,
+    used: RefCell<HashSet<String>>,
+    /// The footnotes being converted, to catch footnotes that refer to themselves
+    expanding: RefCell<Vec<String>>,
+}
+
+impl Footnotes {
+    /// Take the footnote definitions out of a file's lines.
+    /// A definition is `[^mark]: text` (the colon is optional), continued by the
+    /// following lines up to a blank line, and by indented lines after that,
+    /// which may hold further paragraphs.
+    /// Returns the remaining lines and the definitions.
+    pub fn collect(lines: Vec<String>) -> (Vec<String>, Footnotes) {
+        let mut footnotes = Footnotes::default();
+        let mut rest = Vec::with_capacity(lines.len());
+        let mut fence: Option<Fence> = None;
+        let mut lines = lines.into_iter().peekable();
+        while let Some(line) = lines.next() {
+            // Leave code blocks alone; they may well contain things that look like footnotes
+            if let Some(open) = &fence {
+                if open.is_closed_by(&line) {
+                    fence = None;
+                }
+                rest.push(line);
+                continue;
+            }
+            if let Some(cap) = RE_FENCE.captures(&line) {
+                fence = Some(Fence {
+                    ch: cap["fence"].chars().next().unwrap(),
+                    len: cap["fence"].len(),
+                    indent: cap["indent"].len(),
+                });
+                rest.push(line);
+                continue;
+            }
+            let Some(cap) = RE_FOOTNOTE_DEF
+                .captures(&line)
+                .filter(|_| indent_width(&line) < 4)
+            else {
+                rest.push(line);
+                continue;
+            };
+
+            let mut body = vec![cap["body"].to_owned()];
+            let mut blank_lines = 0;
+            while let Some(next) = lines.peek() {
+                if next.trim().is_empty() {
+                    blank_lines += 1;
+                } else if indent_width(next) >= 4 {
+                    body.extend(std::iter::repeat_n(String::new(), blank_lines));
+                    body.push(strip_indent(next, 4));
+                    blank_lines = 0;
+                } else if blank_lines == 0 && !RE_FOOTNOTE_DEF.is_match(next) {
+                    body.push(next.trim_start().to_owned());
+                } else {
+                    break;
+                }
+                lines.next();
+            }
+            // The blank lines after the definition still separate what's around it
+            rest.extend(std::iter::repeat_n(String::new(), blank_lines));
+
+            let mark = cap["mark"].to_owned();
+            if footnotes.defs.contains_key(&mark) {
+                eprintln!(
+                    "Footnote [^{}] is defined more than once; the first definition is used.",
+                    mark
+                );
+            } else {
+                footnotes.order.push(mark.clone());
+                footnotes.defs.insert(mark, body);
+            }
+        }
+        (rest, footnotes)
+    }
+
+    /// The tex of the footnote with the given mark, produced by `convert` from its markdown.
+    /// None, with a warning, if there's no such footnote or it refers to itself.
+    pub fn expand(&self, mark: &str, convert: impl FnOnce(&[String]) -> String) -> Option<String> {
+        let Some(body) = self.defs.get(mark) else {
+            eprintln!("Footnote [^{}] is referenced but never defined.", mark);
+            return None;
+        };
+        if self.expanding.borrow().iter().any(|m| m == mark) {
+            eprintln!("Footnote [^{}] refers to itself.", mark);
+            return None;
+        }
+        self.used.borrow_mut().insert(mark.to_owned());
+        self.expanding.borrow_mut().push(mark.to_owned());
+        let tex = convert(body);
+        self.expanding.borrow_mut().pop();
+        Some(tex)
+    }
+
+    /// Warn about footnotes that were defined but never referenced.
+    pub fn report_unused(&self) {
+        let used = self.used.borrow();
+        for mark in self.order.iter().filter(|mark| !used.contains(*mark)) {
+            eprintln!("Footnote [^{}] is defined but never referenced.", mark);
+        }
+    }
+}
+
+#[cfg(test)]
+mod footnote_tests {
+    use super::*;
+
+    #[test]
+    fn test_collect_footnotes() {
+        let lines = [
+            "Text[^a] and[^b].",
+            "",
+            "[^a]: First line",
+            "continued.",
+            "",
+            "    Second paragraph.",
+            "",
+            "```",
+            "[^c]: not a footnote",
+            "```",
+            "[^b] Old style.",
+            "[^a]: Again.",
+            "",
+            "After.",
+        ]
+        .map(str::to_owned)
+        .to_vec();
+        let (rest, footnotes) = Footnotes::collect(lines);
+        assert_eq!(
+            rest,
+            vec![
+                "Text[^a] and[^b].",
+                "",
+                "",
+                "```",
+                "[^c]: not a footnote",
+                "```",
+                "",
+                "After."
+            ]
+        );
+        assert_eq!(
+            footnotes.defs["a"],
+            vec!["First line", "continued.", "", "Second paragraph."]
+        );
+        assert_eq!(footnotes.defs["b"], vec!["Old style."]);
+        assert_eq!(footnotes.order, vec!["a", "b"]);
+
+        assert_eq!(
+            footnotes.expand("a", |body| body.join(" ")).as_deref(),
+            Some("First line continued.  Second paragraph.")
+        );
+        assert!(footnotes.expand("missing", |_| String::new()).is_none());
+        assert!(footnotes.used.borrow().contains("a"));
+        assert!(!footnotes.used.borrow().contains("b"));
+    }
+}
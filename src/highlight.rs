@@ -0,0 +1,630 @@
+use clap::ValueEnum;
+
+/// The kinds of token the highlighter distinguishes.
+/// Each theme assigns a colour and font to every class except `Plain`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TokenClass {
+    Plain,
+    Keyword,
+    Type,
+    Constant,
+    String,
+    Number,
+    Comment,
+    Preprocessor,
+}
+
+/// Colour schemes for highlighted code.
+/// Colours are written inline as HTML hex values so no colour definitions are needed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum HighlightTheme {
+    /// Dark colours on a light background
+    #[default]
+    Default,
+    /// The accent colours of the solarized palette
+    Solarized,
+    /// No colour; keywords in bold and comments in italics
+    Monochrome,
+}
+
+struct Style {
+    color: Option<&'static str>,
+    bold: bool,
+    italic: bool,
+}
+
+impl HighlightTheme {
+    fn style(&self, class: TokenClass) -> Style {
+        let (color, bold, italic) = match (self, class) {
+            (_, TokenClass::Plain) => (None, false, false),
+            (HighlightTheme::Default, TokenClass::Keyword) => (Some("0033B3"), true, false),
+            (HighlightTheme::Default, TokenClass::Type) => (Some("008080"), false, false),
+            (HighlightTheme::Default, TokenClass::Constant) => (Some("871094"), false, false),
+            (HighlightTheme::Default, TokenClass::String) => (Some("067D17"), false, false),
+            (HighlightTheme::Default, TokenClass::Number) => (Some("1750EB"), false, false),
+            (HighlightTheme::Default, TokenClass::Comment) => (Some("8C8C8C"), false, true),
+            (HighlightTheme::Default, TokenClass::Preprocessor) => (Some("9E880D"), false, false),
+            (HighlightTheme::Solarized, TokenClass::Keyword) => (Some("859900"), false, false),
+            (HighlightTheme::Solarized, TokenClass::Type) => (Some("B58900"), false, false),
+            (HighlightTheme::Solarized, TokenClass::Constant) => (Some("CB4B16"), false, false),
+            (HighlightTheme::Solarized, TokenClass::String) => (Some("2AA198"), false, false),
+            (HighlightTheme::Solarized, TokenClass::Number) => (Some("D33682"), false, false),
+            (HighlightTheme::Solarized, TokenClass::Comment) => (Some("93A1A1"), false, true),
+            (HighlightTheme::Solarized, TokenClass::Preprocessor) => (Some("6C71C4"), false, false),
+            (HighlightTheme::Monochrome, TokenClass::Keyword) => (None, true, false),
+            (HighlightTheme::Monochrome, TokenClass::Comment) => (None, false, true),
+            (HighlightTheme::Monochrome, _) => (None, false, false),
+        };
+        Style {
+            color,
+            bold,
+            italic,
+        }
+    }
+}
+
+/// A string literal syntax: the opening and closing delimiters,
+/// whether it may continue onto following lines, and whether backslash escapes apply.
+struct StringSyntax {
+    open: &'static str,
+    close: &'static str,
+    multiline: bool,
+    escapes: bool,
+}
+
+const fn string(
+    open: &'static str,
+    close: &'static str,
+    multiline: bool,
+    escapes: bool,
+) -> StringSyntax {
+    StringSyntax {
+        open,
+        close,
+        multiline,
+        escapes,
+    }
+}
+
+/// Just enough of a language's lexical structure to colour it sensibly.
+struct Grammar {
+    names: &'static [&'static str],
+    keywords: &'static [&'static str],
+    types: &'static [&'static str],
+    constants: &'static [&'static str],
+    line_comments: &'static [&'static str],
+    block_comment: Option<(&'static str, &'static str)>,
+    strings: &'static [StringSyntax],
+    /// Rust style character literals, which must be told apart from lifetimes
+    char_literals: bool,
+    /// Identifiers starting with a capital letter are types
+    capitalized_types: bool,
+    /// Lines starting with this are preprocessor directives
+    preprocessor_line: Option<&'static str>,
+    /// Attributes or decorators starting with this run to the matching close bracket,
+    /// or to the end of the identifier if no bracket follows
+    attribute_prefix: Option<&'static str>,
+}
+
+static GRAMMARS: &[Grammar] = &[
+    Grammar {
+        names: &["rust", "rs"],
+        keywords: &[
+            "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum",
+            "extern", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move",
+            "mut", "pub", "ref", "return", "self", "static", "struct", "super", "trait", "type",
+            "unsafe", "use", "where", "while", "yield",
+        ],
+        types: &[
+            "bool", "char", "f32", "f64", "i8", "i16", "i32", "i64", "i128", "isize", "str", "u8",
+            "u16", "u32", "u64", "u128", "usize", "Self",
+        ],
+        constants: &["true", "false", "None", "Some", "Ok", "Err"],
+        line_comments: &["//"],
+        block_comment: Some(("/*", "*/")),
+        strings: &[
+            string("r#\"", "\"#", true, false),
+            string("\"", "\"", true, true),
+        ],
+        char_literals: true,
+        capitalized_types: true,
+        preprocessor_line: None,
+        attribute_prefix: Some("#"),
+    },
+    Grammar {
+        names: &["python", "py"],
+        keywords: &[
+            "and", "as", "assert", "async", "await", "break", "class", "continue", "def", "del",
+            "elif", "else", "except", "finally", "for", "from", "global", "if", "import", "in",
+            "is", "lambda", "nonlocal", "not", "or", "pass", "raise", "return", "try", "while",
+            "with", "yield",
+        ],
+        types: &[
+            "bool", "bytes", "dict", "float", "int", "list", "object", "set", "str", "tuple",
+        ],
+        constants: &["True", "False", "None", "self"],
+        line_comments: &["#"],
+        block_comment: None,
+        strings: &[
+            string("\"\"\"", "\"\"\"", true, true),
+            string("'''", "'''", true, true),
+            string("\"", "\"", false, true),
+            string("'", "'", false, true),
+        ],
+        char_literals: false,
+        capitalized_types: true,
+        preprocessor_line: None,
+        attribute_prefix: Some("@"),
+    },
+    Grammar {
+        names: &["c", "h", "cpp", "c++", "cxx", "hpp", "cc"],
+        keywords: &[
+            "auto",
+            "break",
+            "case",
+            "catch",
+            "class",
+            "const",
+            "constexpr",
+            "continue",
+            "default",
+            "delete",
+            "do",
+            "else",
+            "enum",
+            "explicit",
+            "extern",
+            "for",
+            "friend",
+            "goto",
+            "if",
+            "inline",
+            "namespace",
+            "new",
+            "noexcept",
+            "operator",
+            "private",
+            "protected",
+            "public",
+            "register",
+            "return",
+            "sizeof",
+            "static",
+            "struct",
+            "switch",
+            "template",
+            "this",
+            "throw",
+            "try",
+            "typedef",
+            "typename",
+            "union",
+            "using",
+            "virtual",
+            "volatile",
+            "while",
+        ],
+        types: &[
+            "bool", "char", "double", "float", "int", "long", "short", "signed", "unsigned",
+            "void", "size_t", "int8_t", "int16_t", "int32_t", "int64_t", "uint8_t", "uint16_t",
+            "uint32_t", "uint64_t", "std", "string", "vector",
+        ],
+        constants: &["true", "false", "NULL", "nullptr"],
+        line_comments: &["//"],
+        block_comment: Some(("/*", "*/")),
+        strings: &[
+            string("\"", "\"", false, true),
+            string("'", "'", false, true),
+        ],
+        char_literals: false,
+        capitalized_types: false,
+        preprocessor_line: Some("#"),
+        attribute_prefix: None,
+    },
+];
+
+fn grammar(lang: &str) -> Option<&'static Grammar> {
+    let lang = lang.to_lowercase();
+    GRAMMARS.iter().find(|g| g.names.contains(&lang.as_str()))
+}
+
+/// Whether the highlighter has a grammar for the language.
+pub fn is_supported(lang: &str) -> bool {
+    grammar(lang).is_some()
+}
+
+/// What an unfinished line leaves open for the next one.
+#[derive(Clone, Debug, PartialEq)]
+enum Carry {
+    Nothing,
+    BlockComment,
+    /// Index into the grammar's string syntaxes
+    String(usize),
+}
+
+/// Tokenizes a code block one line at a time,
+/// carrying multi-line comments and strings from one line to the next.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Highlighter {
+    lang: String,
+    carry: Carry,
+}
+
+impl Highlighter {
+    pub fn new(lang: &str) -> Highlighter {
+        Highlighter {
+            lang: lang.to_owned(),
+            carry: Carry::Nothing,
+        }
+    }
+
+    /// Split a line of code into runs of the same token class.
+    /// Unknown languages come back as a single plain run.
+    pub fn tokenize(&mut self, line: &str) -> Vec<(TokenClass, String)> {
+        match grammar(&self.lang) {
+            Some(g) => {
+                let mut tokens = Vec::new();
+                self.carry = tokenize_line(g, self.carry.clone(), line, &mut tokens);
+                merge(tokens)
+            }
+            None => vec![(TokenClass::Plain, line.to_owned())],
+        }
+    }
+
+    /// Highlight code for use in running text, e.g., inline code in a paragraph,
+    /// escaping it so it is safe anywhere, including in command arguments.
+    pub fn text_line(&mut self, line: &str, theme: HighlightTheme) -> String {
+        self.tokenize(line)
+            .iter()
+            .map(|(class, text)| styled(theme, *class, &escape_text(text)))
+            .collect()
+    }
+
+    /// Highlight one line for a fancyvrb `Verbatim` environment
+    /// opened with `commandchars=\\\{\}`.
+    pub fn verbatim_line(&mut self, line: &str, theme: HighlightTheme) -> String {
+        self.tokenize(line)
+            .iter()
+            .map(|(class, text)| styled(theme, *class, &escape_verbatim(text)))
+            .collect()
+    }
+}
+
+fn tokenize_line(
+    g: &Grammar,
+    mut carry: Carry,
+    line: &str,
+    tokens: &mut Vec<(TokenClass, String)>,
+) -> Carry {
+    let mut rest = line;
+
+    // Finish whatever the previous line left open
+    match carry {
+        Carry::BlockComment => {
+            let (_, close) = g
+                .block_comment
+                .expect("Only grammars with block comments carry them");
+            match rest.find(close) {
+                Some(pos) => {
+                    let end = pos + close.len();
+                    tokens.push((TokenClass::Comment, rest[..end].to_owned()));
+                    rest = &rest[end..];
+                    carry = Carry::Nothing;
+                }
+                None => {
+                    tokens.push((TokenClass::Comment, rest.to_owned()));
+                    return carry;
+                }
+            }
+        }
+        Carry::String(idx) => match find_string_end(&g.strings[idx], rest) {
+            Some(end) => {
+                tokens.push((TokenClass::String, rest[..end].to_owned()));
+                rest = &rest[end..];
+                carry = Carry::Nothing;
+            }
+            None => {
+                tokens.push((TokenClass::String, rest.to_owned()));
+                return carry;
+            }
+        },
+        Carry::Nothing => {}
+    }
+
+    if let Some(prefix) = g.preprocessor_line {
+        if rest.trim_start().starts_with(prefix) {
+            tokens.push((TokenClass::Preprocessor, rest.to_owned()));
+            return carry;
+        }
+    }
+
+    while let Some(ch) = rest.chars().next() {
+        if ch.is_whitespace() {
+            let end = rest
+                .find(|c: char| !c.is_whitespace())
+                .unwrap_or(rest.len());
+            tokens.push((TokenClass::Plain, rest[..end].to_owned()));
+            rest = &rest[end..];
+        } else if g.line_comments.iter().any(|c| rest.starts_with(c)) {
+            tokens.push((TokenClass::Comment, rest.to_owned()));
+            rest = "";
+        } else if let Some((open, close)) = g.block_comment.filter(|(o, _)| rest.starts_with(o)) {
+            match rest[open.len()..].find(close) {
+                Some(pos) => {
+                    let end = open.len() + pos + close.len();
+                    tokens.push((TokenClass::Comment, rest[..end].to_owned()));
+                    rest = &rest[end..];
+                }
+                None => {
+                    tokens.push((TokenClass::Comment, rest.to_owned()));
+                    return Carry::BlockComment;
+                }
+            }
+        } else if let Some(idx) = g.strings.iter().position(|s| rest.starts_with(s.open)) {
+            let syntax = &g.strings[idx];
+            let open = syntax.open.len();
+            match find_string_end(syntax, &rest[open..]) {
+                Some(end) => {
+                    tokens.push((TokenClass::String, rest[..open + end].to_owned()));
+                    rest = &rest[open + end..];
+                }
+                None => {
+                    tokens.push((TokenClass::String, rest.to_owned()));
+                    return if syntax.multiline {
+                        Carry::String(idx)
+                    } else {
+                        Carry::Nothing
+                    };
+                }
+            }
+        } else if g.char_literals && ch == '\'' {
+            let end = char_literal_len(rest);
+            let class = if end > 1 {
+                TokenClass::String
+            } else {
+                TokenClass::Plain
+            };
+            tokens.push((class, rest[..end].to_owned()));
+            rest = &rest[end..];
+        } else if let Some(prefix) = g.attribute_prefix.filter(|p| rest.starts_with(p)) {
+            let end = attribute_len(rest, prefix.len());
+            tokens.push((TokenClass::Preprocessor, rest[..end].to_owned()));
+            rest = &rest[end..];
+        } else if ch.is_ascii_digit() {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+                .unwrap_or(rest.len());
+            tokens.push((TokenClass::Number, rest[..end].to_owned()));
+            rest = &rest[end..];
+        } else if ch.is_alphabetic() || ch == '_' {
+            let end = rest
+                .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            let word = &rest[..end];
+            tokens.push((classify(g, word), word.to_owned()));
+            rest = &rest[end..];
+        } else {
+            tokens.push((TokenClass::Plain, ch.to_string()));
+            rest = &rest[ch.len_utf8()..];
+        }
+    }
+    carry
+}
+
+/// Find the byte offset just past the closing delimiter of a string,
+/// given the text following the opening delimiter.
+fn find_string_end(syntax: &StringSyntax, text: &str) -> Option<usize> {
+    let mut idx = 0;
+    while idx < text.len() {
+        let rest = &text[idx..];
+        if syntax.escapes && rest.starts_with('\\') {
+            idx += 1 + rest[1..].chars().next().map_or(0, char::len_utf8);
+        } else if rest.starts_with(syntax.close) {
+            return Some(idx + syntax.close.len());
+        } else {
+            idx += rest.chars().next().map_or(1, char::len_utf8);
+        }
+    }
+    None
+}
+
+/// The length of a character literal such as `'a'` or `'\n'` at the start of the text,
+/// or 1 if the quote starts a lifetime instead.
+fn char_literal_len(text: &str) -> usize {
+    let mut chars = text.char_indices().skip(1);
+    match chars.next() {
+        Some((_, '\\')) => text[2..].find('\'').map_or(1, |pos| pos + 3),
+        Some((_, ch)) => match chars.next() {
+            Some((pos, '\'')) if ch != '\'' => pos + 1,
+            _ => 1,
+        },
+        None => 1,
+    }
+}
+
+/// The length of an attribute or decorator: a bracketed group if one follows the prefix,
+/// otherwise a dotted name.
+fn attribute_len(text: &str, prefix: usize) -> usize {
+    let after = &text[prefix..];
+    let bang = usize::from(after.starts_with('!'));
+    if after[bang..].starts_with('[') {
+        let mut depth = 0;
+        for (pos, ch) in after.char_indices().skip(bang) {
+            match ch {
+                '[' => depth += 1,
+                ']' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return prefix + pos + 1;
+                    }
+                }
+                _ => {}
+            }
+        }
+        text.len()
+    } else {
+        prefix
+            + after
+                .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.'))
+                .unwrap_or(after.len())
+    }
+}
+
+fn classify(g: &Grammar, word: &str) -> TokenClass {
+    if g.keywords.contains(&word) {
+        TokenClass::Keyword
+    } else if g.constants.contains(&word) {
+        TokenClass::Constant
+    } else if g.types.contains(&word)
+        || (g.capitalized_types && word.starts_with(|c: char| c.is_uppercase()))
+    {
+        TokenClass::Type
+    } else {
+        TokenClass::Plain
+    }
+}
+
+/// Join neighbouring tokens of the same class so the tex has fewer commands in it.
+fn merge(tokens: Vec<(TokenClass, String)>) -> Vec<(TokenClass, String)> {
+    let mut merged: Vec<(TokenClass, String)> = Vec::with_capacity(tokens.len());
+    for (class, text) in tokens {
+        match merged.last_mut() {
+            Some((last, last_text)) if *last == class => last_text.push_str(&text),
+            _ => merged.push((class, text)),
+        }
+    }
+    merged
+}
+
+/// With `commandchars=\\\{\}` the backslash and braces are command characters,
+/// so literal ones are written by character code.
+fn escape_verbatim(text: &str) -> String {
+    let mut res = String::with_capacity(text.len());
+    for ch in text.chars() {
+        match ch {
+            '\\' => res.push_str("\\char92{}"),
+            '{' => res.push_str("\\char123{}"),
+            '}' => res.push_str("\\char125{}"),
+            _ => res.push(ch),
+        }
+    }
+    res
+}
+
+/// Escape every character that means something to tex,
+/// so the text comes out exactly as written.
+pub fn escape_text(text: &str) -> String {
+    let mut res = String::with_capacity(text.len());
+    for ch in text.chars() {
+        match ch {
+            '\\' => res.push_str("\\textbackslash{}"),
+            '{' | '}' | '$' | '&' | '#' | '_' | '%' => {
+                res.push('\\');
+                res.push(ch);
+            }
+            '^' => res.push_str("\\textasciicircum{}"),
+            '~' => res.push_str("\\textasciitilde{}"),
+            _ => res.push(ch),
+        }
+    }
+    res
+}
+
+fn styled(theme: HighlightTheme, class: TokenClass, text: &str) -> String {
+    let style = theme.style(class);
+    if text.trim().is_empty() {
+        return text.to_owned();
+    }
+    let mut res = text.to_owned();
+    if style.bold {
+        res = format!("\\textbf{{{}}}", res);
+    }
+    if style.italic {
+        res = format!("\\textit{{{}}}", res);
+    }
+    if let Some(color) = style.color {
+        res = format!("\\textcolor[HTML]{{{}}}{{{}}}", color, res);
+    }
+    res
+}
+
+#[cfg(test)]
+mod highlight_tests {
+    use super::*;
+
+    #[test]
+    fn test_tokenize_rust() {
+        let mut h = Highlighter::new("rust");
+        let tokens = h.tokenize("let s: &str = \"a{b}\"; // done");
+        assert_eq!(
+            tokens,
+            vec![
+                (TokenClass::Keyword, "let".to_owned()),
+                (TokenClass::Plain, " s: &".to_owned()),
+                (TokenClass::Type, "str".to_owned()),
+                (TokenClass::Plain, " = ".to_owned()),
+                (TokenClass::String, "\"a{b}\"".to_owned()),
+                (TokenClass::Plain, "; ".to_owned()),
+                (TokenClass::Comment, "// done".to_owned()),
+            ]
+        );
+
+        let line = h.verbatim_line("fn f<'a>(c: char) { '}' }", HighlightTheme::Monochrome);
+        assert_eq!(
+            line,
+            "\\textbf{fn} f<'a>(c: char) \\char123{} '\\char125{}' \\char125{}"
+        );
+    }
+
+    #[test]
+    fn test_tokenize_python() {
+        let mut h = Highlighter::new("py");
+        assert_eq!(
+            h.tokenize("@cache"),
+            vec![(TokenClass::Preprocessor, "@cache".to_owned())]
+        );
+        assert_eq!(
+            h.tokenize("def f(x: int) -> None: \"\"\"Doc"),
+            vec![
+                (TokenClass::Keyword, "def".to_owned()),
+                (TokenClass::Plain, " f(x: ".to_owned()),
+                (TokenClass::Type, "int".to_owned()),
+                (TokenClass::Plain, ") -> ".to_owned()),
+                (TokenClass::Constant, "None".to_owned()),
+                (TokenClass::Plain, ": ".to_owned()),
+                (TokenClass::String, "\"\"\"Doc".to_owned()),
+            ]
+        );
+        assert_eq!(
+            h.tokenize("more\"\"\" # done"),
+            vec![
+                (TokenClass::String, "more\"\"\"".to_owned()),
+                (TokenClass::Plain, " ".to_owned()),
+                (TokenClass::Comment, "# done".to_owned()),
+            ]
+        );
+        assert!(!is_supported("javascript"));
+    }
+
+    #[test]
+    fn test_multiline_comment_carry() {
+        let mut h = Highlighter::new("c");
+        assert_eq!(
+            h.tokenize("x = 1; /* start"),
+            vec![
+                (TokenClass::Plain, "x = ".to_owned()),
+                (TokenClass::Number, "1".to_owned()),
+                (TokenClass::Plain, "; ".to_owned()),
+                (TokenClass::Comment, "/* start".to_owned()),
+            ]
+        );
+        assert_eq!(
+            h.tokenize("end */ return"),
+            vec![
+                (TokenClass::Comment, "end */".to_owned()),
+                (TokenClass::Plain, " ".to_owned()),
+                (TokenClass::Keyword, "return".to_owned()),
+            ]
+        );
+    }
+}
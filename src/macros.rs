@@ -0,0 +1,446 @@
+use anyhow::{anyhow, bail, Error};
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashSet};
+use std::fs;
+use std::path::Path;
+
+lazy_static! {
+    /// A command declared in a tex macros file, e.g. `\newcommand{\R}` or `\def\R`
+    static ref RE_TEX_DECLARATION: Regex = Regex::new(
+        r#"\\(?:(?:re|provide)?newcommand\*?|DeclareMathOperator\*?|DeclareRobustCommand\*?)\s*\{?\s*\\(?<name>[A-Za-z]+)|\\(?:def|let)\s*\\(?<defname>[A-Za-z]+)"#
+    )
+    .unwrap();
+    static ref RE_ARGUMENT: Regex = Regex::new(r#"#(?<n>[1-9])"#).unwrap();
+    static ref RE_FRONT_MATTER_KEY: Regex =
+        Regex::new(r#"^(?<key>[A-Za-z_-]+):\s*(?<value>.*?)\s*$"#).unwrap();
+    static ref RE_FRONT_MATTER_ENTRY: Regex =
+        Regex::new(r#"^\s+(?<name>[^:\s]+):\s*(?<value>.*?)\s*$"#).unwrap();
+    static ref KNOWN_COMMANDS: HashSet<&'static str> = KNOWN.iter().copied().collect();
+}
+
+/// The commands LaTeX, amsmath and amssymb provide for use in math, without the backslash,
+/// along with the few from latexsym and mathrsfs that documents commonly use.
+#[rustfmt::skip]
+const KNOWN: &[&str] = &[
+    // Greek letters
+    "alpha", "beta", "gamma", "delta", "epsilon", "varepsilon", "zeta", "eta", "theta", "vartheta",
+    "iota", "kappa", "varkappa", "lambda", "mu", "nu", "xi", "pi", "varpi", "rho", "varrho",
+    "sigma", "varsigma", "tau", "upsilon", "phi", "varphi", "chi", "psi", "omega", "digamma",
+    "Gamma", "Delta", "Theta", "Lambda", "Xi", "Pi", "Sigma", "Upsilon", "Phi", "Psi", "Omega",
+    "varGamma", "varDelta", "varTheta", "varLambda", "varXi", "varPi", "varSigma", "varUpsilon",
+    "varPhi", "varPsi", "varOmega",
+    // Letter-like symbols
+    "aleph", "beth", "gimel", "daleth", "hbar", "hslash", "ell", "wp", "Re", "Im", "partial",
+    "infty", "nabla", "forall", "exists", "nexists", "emptyset", "varnothing", "imath", "jmath",
+    "Bbbk", "complement", "eth", "Finv", "Game", "mho", "prime", "backprime", "top", "bot", "angle",
+    "measuredangle", "sphericalangle", "triangle", "triangledown", "square", "blacksquare",
+    "lozenge", "blacklozenge", "bigstar", "blacktriangle", "blacktriangledown", "surd", "neg",
+    "lnot", "flat", "natural", "sharp", "clubsuit", "diamondsuit", "heartsuit", "spadesuit",
+    "checkmark", "circledR", "maltese", "dag", "ddag", "S", "P", "copyright", "pounds", "dagger",
+    "ddagger", "Box", "Diamond", "vartriangle", "circledS", "diagup", "diagdown", "backepsilon",
+    "restriction", "mathsterling",
+    // Binary operators
+    "pm", "mp", "times", "div", "cdot", "ast", "star", "circ", "bullet", "cap", "cup", "uplus",
+    "sqcap", "sqcup", "vee", "lor", "wedge", "land", "setminus", "smallsetminus", "wr", "diamond",
+    "bigtriangleup", "bigtriangledown", "triangleleft", "triangleright", "lhd", "rhd", "unlhd",
+    "unrhd", "oplus", "ominus", "otimes", "oslash", "odot", "bigcirc", "amalg", "boxplus",
+    "boxminus", "boxtimes", "boxdot", "circledast", "circledcirc", "circleddash", "dotplus",
+    "divideontimes", "ltimes", "rtimes", "leftthreetimes", "rightthreetimes", "curlyvee",
+    "curlywedge", "barwedge", "doublebarwedge", "veebar", "centerdot", "intercal", "Cap", "Cup",
+    "doublecap", "doublecup", "ldotp", "cdotp", "And",
+    // Relations
+    "leq", "le", "geq", "ge", "neq", "ne", "equiv", "approx", "approxeq", "sim", "simeq", "cong",
+    "propto", "ll", "gg", "lll", "ggg", "subset", "supset", "subseteq", "supseteq", "subsetneq",
+    "supsetneq", "nsubseteq", "nsupseteq", "Subset", "Supset", "sqsubset", "sqsupset", "sqsubseteq",
+    "sqsupseteq", "in", "ni", "notin", "owns", "vdash", "dashv", "models", "vDash", "Vdash",
+    "Vvdash", "nvdash", "nvDash", "mid", "nmid", "parallel", "nparallel", "perp", "smile", "frown",
+    "asymp", "bowtie", "Join", "doteq", "doteqdot", "Doteq", "prec", "succ", "preceq", "succeq",
+    "nprec", "nsucc", "leqslant", "geqslant", "leqq", "geqq", "lneq", "gneq", "lneqq", "gneqq",
+    "nleq", "ngeq", "nleqslant", "ngeqslant", "nless", "ngtr", "lesssim", "gtrsim", "lessapprox",
+    "gtrapprox", "lessgtr", "gtrless", "eqsim", "backsim", "backsimeq", "thicksim", "thickapprox",
+    "nsim", "ncong", "triangleq", "circeq", "bumpeq", "Bumpeq", "risingdotseq", "fallingdotseq",
+    "therefore", "because", "between", "pitchfork", "shortmid", "shortparallel", "varpropto",
+    "trianglelefteq", "trianglerighteq", "vartriangleleft", "vartriangleright", "ntriangleleft",
+    "ntriangleright", "coloneqq", "colon", "eqslantless", "lessdot", "llless", "lesseqgtr",
+    "lesseqqgtr", "subseteqq", "preccurlyeq", "curlyeqprec", "precsim", "precapprox", "smallsmile",
+    "smallfrown", "eqslantgtr", "gtrdot", "gggtr", "gtreqless", "gtreqqless", "eqcirc", "supseteqq",
+    "succcurlyeq", "curlyeqsucc", "succsim", "succapprox", "blacktriangleleft",
+    "blacktriangleright", "nleqq", "lvertneqq", "lnsim", "lnapprox", "npreceq", "precnsim",
+    "precnapprox", "nshortmid", "ntrianglelefteq", "varsubsetneq", "subsetneqq", "varsubsetneqq",
+    "ngeqq", "gvertneqq", "gnsim", "gnapprox", "nsucceq", "succnsim", "succnapprox",
+    "nshortparallel", "nVDash", "nVdash", "ntrianglerighteq", "nsupseteqq", "varsupsetneq",
+    "supsetneqq", "varsupsetneqq",
+    // Arrows
+    "leftarrow", "gets", "rightarrow", "to", "leftrightarrow", "Leftarrow", "Rightarrow",
+    "Leftrightarrow", "longleftarrow", "longrightarrow", "longleftrightarrow", "Longleftarrow",
+    "Longrightarrow", "Longleftrightarrow", "iff", "implies", "impliedby", "mapsto", "longmapsto",
+    "hookleftarrow", "hookrightarrow", "leftharpoonup", "leftharpoondown", "rightharpoonup",
+    "rightharpoondown", "rightleftharpoons", "leftrightharpoons", "uparrow", "downarrow",
+    "updownarrow", "Uparrow", "Downarrow", "Updownarrow", "nearrow", "searrow", "swarrow",
+    "nwarrow", "leadsto", "twoheadrightarrow", "twoheadleftarrow", "rightarrowtail",
+    "leftarrowtail", "looparrowright", "looparrowleft", "curvearrowright", "curvearrowleft",
+    "circlearrowright", "circlearrowleft", "rightsquigarrow", "leftrightsquigarrow",
+    "upharpoonleft", "upharpoonright", "downharpoonleft", "downharpoonright", "nleftarrow",
+    "nrightarrow", "nLeftarrow", "nRightarrow", "nleftrightarrow", "nLeftrightarrow", "xleftarrow",
+    "xrightarrow", "Lsh", "Rsh", "multimap", "dashrightarrow", "dashleftarrow", "leftleftarrows",
+    "leftrightarrows", "Lleftarrow", "upuparrows", "rightrightarrows", "rightleftarrows",
+    "Rrightarrow", "downdownarrows",
+    // Big operators
+    "sum", "prod", "coprod", "int", "iint", "iiint", "iiiint", "idotsint", "oint", "bigcap",
+    "bigcup", "bigsqcup", "bigvee", "bigwedge", "bigodot", "bigoplus", "bigotimes", "biguplus",
+    "smallint",
+    // Named operators
+    "arccos", "arcsin", "arctan", "arg", "cos", "cosh", "cot", "coth", "csc", "deg", "det", "dim",
+    "exp", "gcd", "hom", "inf", "injlim", "ker", "lg", "lim", "liminf", "limsup", "ln", "log",
+    "max", "min", "Pr", "projlim", "sec", "sin", "sinh", "sup", "tan", "tanh", "varinjlim",
+    "varprojlim", "varliminf", "varlimsup", "operatorname", "mod", "bmod", "pmod", "pod",
+    // Accents
+    "hat", "widehat", "tilde", "widetilde", "bar", "overline", "underline", "vec", "dot", "ddot",
+    "dddot", "ddddot", "acute", "grave", "breve", "check", "mathring", "overrightarrow",
+    "overleftarrow", "overleftrightarrow", "underrightarrow", "underleftarrow",
+    "underleftrightarrow", "overbrace", "underbrace", "Hat", "Tilde", "Bar", "Vec", "Dot", "Ddot",
+    "Breve", "Acute", "Grave", "Check",
+    // Fonts and text
+    "mathbb", "mathcal", "mathfrak", "mathrm", "mathbf", "mathit", "mathsf", "mathtt", "mathnormal",
+    "boldsymbol", "bm", "pmb", "text", "textrm", "textbf", "textit", "textsf", "texttt", "textup",
+    "textnormal", "emph", "mbox", "rm", "bf", "it", "sf", "tt", "cal", "displaystyle", "textstyle",
+    "scriptstyle", "scriptscriptstyle", "tiny", "small", "normalsize", "large", "Large", "mathscr",
+    "textsc", "textsl", "textmd", "scriptsize", "footnotesize", "huge", "Huge", "LARGE", "mathop",
+    "mathbin", "mathrel", "mathord", "mathopen", "mathclose", "mathpunct", "mathinner",
+    "mathchoice", "ensuremath", "hbox", "vbox", "makebox", "raisebox",
+    // Structures
+    "frac", "dfrac", "tfrac", "cfrac", "binom", "dbinom", "tbinom", "genfrac", "sqrt", "root", "of",
+    "over", "choose", "atop", "stackrel", "overset", "underset", "sideset", "substack", "boxed",
+    "fbox", "phantom", "hphantom", "vphantom", "smash", "mathstrut", "strut", "cancel", "not",
+    "limits", "nolimits", "displaylimits", "buildrel", "brace", "brack", "leftroot", "uproot",
+    "hdotsfor", "rule",
+    // Delimiters
+    "left", "right", "middle", "big", "Big", "bigg", "Bigg", "bigl", "bigr", "Bigl", "Bigr",
+    "biggl", "biggr", "Biggl", "Biggr", "bigm", "Bigm", "langle", "rangle", "lceil", "rceil",
+    "lfloor", "rfloor", "lvert", "rvert", "lVert", "rVert", "vert", "Vert", "lbrace", "rbrace",
+    "lbrack", "rbrack", "backslash", "ulcorner", "urcorner", "llcorner", "lrcorner", "lgroup",
+    "rgroup", "lmoustache", "rmoustache", "Arrowvert", "arrowvert", "bracevert",
+    // Dots and spacing
+    "ldots", "cdots", "vdots", "ddots", "dots", "dotsb", "dotsc", "dotsi", "dotsm", "dotso", "quad",
+    "qquad", "enspace", "thinspace", "medspace", "thickspace", "negthinspace", "negmedspace",
+    "negthickspace", "hspace", "vspace", "hfill", "kern", "mkern", "mskip", "hskip", "space",
+    "nobreakspace", "mspace", "hfil",
+    // Structure of display math
+    "begin", "end", "label", "tag", "notag", "nonumber", "ref", "eqref", "intertext",
+    "shortintertext", "hline", "cline", "multicolumn", "cr", "newline", "allowbreak", "nobreak",
+    "noalign", "color", "textcolor", "displaybreak", "allowdisplaybreaks", "shoveleft",
+    "shoveright",
+];
+
+/// The math macros a document may use, besides those LaTeX and its packages provide.
+/// They're declared in the standalone preamble, and the math in the document is
+/// checked against them and the known commands to catch typos.
+#[derive(Debug, Default)]
+pub struct MathMacros {
+    /// The tex declaring the macros
+    declarations: Vec<String>,
+    /// The names of the macros, without the backslash
+    names: BTreeSet<String>,
+    /// The unknown commands already warned about
+    warned: RefCell<HashSet<String>>,
+}
+
+impl MathMacros {
+    /// Load the macros in a file: tex declarations in a `.tex` file, which go into the
+    /// preamble as they are, or `[macros]` and `[operators]` tables in a `.toml` file,
+    /// with the same entries as front matter.
+    pub fn load(&mut self, path: &Path) -> Result<(), Error> {
+        let text = fs::read_to_string(path)
+            .map_err(|err| anyhow!("Unable to read macros file {}: {}", path.display(), err))?;
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("tex") | Some("sty") => {
+                self.add_tex(&text);
+                Ok(())
+            }
+            Some("toml") => self
+                .add_toml(&text)
+                .map_err(|err| anyhow!("Unable to read macros file {}: {}", path.display(), err)),
+            _ => bail!(
+                "Expected a .tex or .toml macros file, however I got {}.",
+                path.display()
+            ),
+        }
+    }
+
+    fn add_tex(&mut self, tex: &str) {
+        for cap in RE_TEX_DECLARATION.captures_iter(tex) {
+            let name = cap.name("name").or_else(|| cap.name("defname")).unwrap();
+            self.names.insert(name.as_str().to_owned());
+        }
+        self.declarations.push(tex.trim_end().to_owned());
+    }
+
+    fn add_toml(&mut self, text: &str) -> Result<(), Error> {
+        let table: toml::Table = text.parse()?;
+        for (key, value) in &table {
+            let operator = match key.as_str() {
+                "macros" => false,
+                "operators" => true,
+                _ => bail!("Unknown table [{}]; expected [macros] or [operators].", key),
+            };
+            let toml::Value::Table(macros) = value else {
+                bail!("Expected [{}] to be a table of macros.", key);
+            };
+            for (name, body) in macros {
+                let toml::Value::String(body) = body else {
+                    bail!("Expected the body of {} to be a string.", name);
+                };
+                self.declare(name, body, operator)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Take the macros from a document's front matter: a `macros` map from name to body,
+    /// and an `operators` map from name to the operator's text.
+    /// ```yaml
+    /// macros:
+    ///   R: \mathbb{R}
+    ///   norm: \left\lVert #1 \right\rVert
+    /// operators:
+    ///   E: E
+    /// ```
+    /// Bodies are taken as written, unless quoted.
+    /// `macros` may instead name a macros file, relative to the document.
+    pub fn add_front_matter(
+        &mut self,
+        front_matter: &[String],
+        base_dir: &Path,
+    ) -> Result<(), Error> {
+        let mut section = None;
+        for line in front_matter {
+            if let Some(cap) = RE_FRONT_MATTER_KEY.captures(line) {
+                section = match &cap["key"] {
+                    "macros" if !cap["value"].is_empty() => {
+                        self.load(&base_dir.join(unquote(&cap["value"])))?;
+                        None
+                    }
+                    "macros" => Some(false),
+                    "operators" => Some(true),
+                    _ => None,
+                };
+            } else if let (Some(operator), Some(cap)) =
+                (section, RE_FRONT_MATTER_ENTRY.captures(line))
+            {
+                self.declare(&cap["name"], &unquote(&cap["value"]), operator)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Declare a macro with `\newcommand`, or an operator with `\DeclareMathOperator`.
+    /// A macro takes as many arguments as the highest `#n` in its body.
+    /// An operator whose name ends with `*` takes limits, like `\lim`.
+    pub fn declare(&mut self, name: &str, body: &str, operator: bool) -> Result<(), Error> {
+        let starred = operator && name.ends_with('*');
+        let name = name.trim_start_matches('\\').trim_end_matches('*');
+        if name.is_empty() || !name.chars().all(|ch| ch.is_ascii_alphabetic()) {
+            bail!(
+                "Math macro names may only have letters, however I got '{}'.",
+                name
+            );
+        }
+        if !self.names.insert(name.to_owned()) {
+            eprintln!(
+                "Math macro \\{} is declared more than once; the first declaration is used.",
+                name
+            );
+            return Ok(());
+        }
+        let declaration = if operator {
+            let star = if starred { "*" } else { "" };
+            format!("\\DeclareMathOperator{}{{\\{}}}{{{}}}", star, name, body)
+        } else {
+            // Redefining a command LaTeX already has needs \renewcommand
+            let command = if KNOWN_COMMANDS.contains(name) {
+                "renewcommand"
+            } else {
+                "newcommand"
+            };
+            let args = RE_ARGUMENT
+                .captures_iter(body)
+                .filter_map(|cap| cap["n"].parse::<usize>().ok())
+                .max()
+                .map_or(String::new(), |n| format!("[{}]", n));
+            format!("\\{}{{\\{}}}{}{{{}}}", command, name, args, body)
+        };
+        self.declarations.push(declaration);
+        Ok(())
+    }
+
+    /// The declarations of the macros, for the standalone preamble.
+    pub fn preamble(&self) -> String {
+        self.declarations
+            .iter()
+            .map(|declaration| format!("{}\n", declaration))
+            .collect()
+    }
+
+    /// Warn about the commands in some math that are neither known nor declared,
+    /// suggesting the nearest one since most are typos.
+    /// Each unknown command is only reported once.
+    pub fn check(&self, math: &str) {
+        for name in commands(math) {
+            if KNOWN_COMMANDS.contains(name) || self.names.contains(name) {
+                continue;
+            }
+            if !self.warned.borrow_mut().insert(name.to_owned()) {
+                continue;
+            }
+            match self.suggest(name) {
+                Some(suggestion) => eprintln!(
+                    "Unknown math command \\{}; did you mean \\{}?",
+                    name, suggestion
+                ),
+                None => eprintln!(
+                    "Unknown math command \\{}; declare it as a math macro if it's one of yours.",
+                    name
+                ),
+            }
+        }
+    }
+
+    /// The known or declared command closest to an unknown one, if any is close.
+    fn suggest(&self, name: &str) -> Option<&str> {
+        let most = if name.len() <= 3 { 1 } else { 2 };
+        KNOWN
+            .iter()
+            .copied()
+            .chain(self.names.iter().map(String::as_str))
+            .map(|candidate| (edit_distance(name, candidate), candidate))
+            .filter(|(distance, _)| *distance <= most)
+            .min_by_key(|(distance, _)| *distance)
+            .map(|(_, candidate)| candidate)
+    }
+}
+
+/// The names of the commands in some tex, e.g. `frac` for `\frac`.
+/// Commands that aren't letters, like `\\` or `\,`, are skipped.
+fn commands(tex: &str) -> Vec<&str> {
+    let mut names = Vec::new();
+    let mut chars = tex.char_indices().peekable();
+    while let Some((_, ch)) = chars.next() {
+        if ch != '\\' {
+            continue;
+        }
+        let Some(&(start, next)) = chars.peek() else {
+            break;
+        };
+        if !next.is_ascii_alphabetic() {
+            chars.next();
+            continue;
+        }
+        let mut end = start;
+        while let Some(&(i, ch)) = chars.peek() {
+            if !ch.is_ascii_alphabetic() {
+                break;
+            }
+            end = i + ch.len_utf8();
+            chars.next();
+        }
+        names.push(&tex[start..end]);
+    }
+    names
+}
+
+/// The number of single character edits that turn one string into another,
+/// counting swapping two neighbouring characters as one edit.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    d[0] = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            d[i][j] = (d[i - 1][j] + 1)
+                .min(d[i][j - 1] + 1)
+                .min(d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    d[a.len()][b.len()]
+}
+
+/// A front matter value without its quotes.
+fn unquote(value: &str) -> String {
+    if let Some(value) = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        value
+            .replace("\\\\", "\u{E000}")
+            .replace("\\\"", "\"")
+            .replace('\u{E000}', "\\")
+    } else if let Some(value) = value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')) {
+        value.replace("''", "'")
+    } else {
+        value.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod macro_tests {
+    use super::*;
+
+    #[test]
+    fn test_math_macros() {
+        let front_matter = [
+            "title: Notes",
+            "macros:",
+            "  R: \\mathbb{R}",
+            "  norm: \"\\\\left\\\\lVert #1 \\\\right\\\\rVert\"",
+            "  R: \\mathbf{R}",
+            "operators:",
+            "  E: E",
+            "  argmax*: arg\\,max",
+        ]
+        .map(str::to_owned);
+        let mut macros = MathMacros::default();
+        macros
+            .add_front_matter(&front_matter, Path::new(""))
+            .unwrap();
+        macros
+            .add_toml("[macros]\nN = '\\mathbb{N}'\nvec = '\\mathbf{#1}'\n")
+            .unwrap();
+        assert_eq!(
+            macros.preamble(),
+            concat!(
+                "\\newcommand{\\R}{\\mathbb{R}}\n",
+                "\\newcommand{\\norm}[1]{\\left\\lVert #1 \\right\\rVert}\n",
+                "\\DeclareMathOperator{\\E}{E}\n",
+                "\\DeclareMathOperator*{\\argmax}{arg\\,max}\n",
+                "\\newcommand{\\N}{\\mathbb{N}}\n",
+                "\\renewcommand{\\vec}[1]{\\mathbf{#1}}\n",
+            )
+        );
+        assert!(macros.add_toml("[colours]\nred = 'f00'\n").is_err());
+        assert!(macros.declare("x1", "x", false).is_err());
+
+        macros.add_tex("\\newcommand{\\Q}{\\mathbb{Q}}\n\\def\\Z{\\mathbb{Z}}\n");
+        assert!(macros.names.contains("Q") && macros.names.contains("Z"));
+
+        assert_eq!(
+            commands("\\frac{a}{b} \\\\ \\, \\norm{x}_\\R"),
+            vec!["frac", "norm", "R"]
+        );
+        assert_eq!(macros.suggest("nrom"), Some("norm"));
+        assert_eq!(macros.suggest("alpah"), Some("alpha"));
+        assert_eq!(macros.suggest("foobar"), None);
+        macros.check("\\alpah + \\alpah");
+        assert_eq!(macros.warned.borrow().len(), 1);
+        macros.check(
+            "f \\colon \\mathscr{A} \\to B, a \\succcurlyeq b \\lessdot c \\dashrightarrow d",
+        );
+        assert_eq!(macros.warned.borrow().len(), 1);
+    }
+}
@@ -1,10 +1,29 @@
+mod attributes;
+mod callout;
+mod code;
 mod converter;
+mod emphasis;
+mod footnotes;
+mod highlight;
+mod macros;
+mod math;
+mod theorem;
 
-use crate::converter::convert;
+use crate::callout::Callout;
+use crate::code::CodeBackend;
+use crate::converter::{
+    convert, postamble, preamble, split_front_matter, Flavor, Options, ThematicBreak,
+};
+use crate::footnotes::FootnoteMode;
+use crate::highlight::HighlightTheme;
+use crate::macros::MathMacros;
+use crate::theorem::{TheoremCounters, TheoremNumbering};
 use anyhow::Error;
 use clap::Parser;
 use std::fs::File;
 use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use std::result::Result;
 
 /// Parse a markdown file and generate a minimally styled LaTeX file,
@@ -15,11 +34,114 @@ struct Args {
     /// Markdown file to parse
     #[arg(short, long)]
     filename: String,
+
+    /// Package used to typeset fenced code blocks
+    #[arg(long, value_enum, default_value_t = CodeBackend::Listings)]
+    code_backend: CodeBackend,
+
+    /// Colours used when the code backend is highlight
+    #[arg(long, value_enum, default_value_t = HighlightTheme::Default)]
+    highlight_theme: HighlightTheme,
+
+    /// How deeply lists may nest.  LaTeX allows four levels of each kind of list;
+    /// deeper lists need this, and the standalone preamble configures enumitem to match.
+    /// Values under four are taken as four
+    #[arg(long)]
+    list_depth: Option<usize>,
+
+    /// Label for unchecked task list items, `- [ ] item` [default: $\square$]
+    #[arg(long)]
+    task_unchecked: Option<String>,
+
+    /// Label for checked task list items, `- [x] item` [default: $\boxtimes$]
+    #[arg(long)]
+    task_checked: Option<String>,
+
+    /// Title of a kind of callout box, `> [!NOTE]`, given as KIND=TITLE, e.g. note=Hinweis.
+    /// May be repeated; used by the standalone preamble
+    #[arg(long, value_parser = callout::parse_setting)]
+    callout_title: Vec<(Callout, String)>,
+
+    /// Colour of a kind of callout box, given as KIND=COLOUR with an xcolor colour,
+    /// e.g. warning=yellow.  May be repeated; used by the standalone preamble
+    #[arg(long, value_parser = callout::parse_setting)]
+    callout_colour: Vec<(Callout, String)>,
+
+    /// Whether theorems, lemmas, definitions, etc. share one counter;
+    /// used by the standalone preamble
+    #[arg(long, value_enum, default_value_t = TheoremCounters::Shared)]
+    theorem_counters: TheoremCounters,
+
+    /// What theorem numbers are reset by; used by the standalone preamble
+    #[arg(long, value_enum, default_value_t = TheoremNumbering::Chapter)]
+    theorem_numbering: TheoremNumbering,
+
+    /// How footnotes are written out.  Inline footnotes read the whole file first
+    /// so that each reference can become a \footnote holding its definition
+    #[arg(long, value_enum, default_value_t = FootnoteMode::Marks)]
+    footnotes: FootnoteMode,
+
+    /// File of math macros, either tex declarations (.tex) or [macros] and [operators]
+    /// tables of name = body (.toml).  Front matter may declare more;
+    /// used by the standalone preamble, and to check the commands in math
+    #[arg(long)]
+    macros: Option<PathBuf>,
+
+    /// What a thematic break, a line of `---`, `***` or `___`, becomes
+    #[arg(long, value_enum, default_value_t = ThematicBreak::Hrule)]
+    thematic_break: ThematicBreak,
+
+    /// The dialect of markdown.  Legacy reads `*bold*` and `_emphasis_` as this tool
+    /// always has; the others read emphasis as CommonMark does
+    #[arg(long, value_enum, default_value_t = Flavor::Legacy)]
+    flavor: Flavor,
+
+    /// Wrap the output in a complete document with a preamble,
+    /// rather than producing a fragment to be \input into another file
+    #[arg(long)]
+    standalone: bool,
 }
 
 fn main() -> Result<(), Error> {
     let args = Args::parse();
-    convert(io::BufReader::new(File::open(&args.filename)?).lines())
-        .for_each(|processed_line| print!("{}", processed_line));
+    let base_dir = Path::new(&args.filename)
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_default();
+    let lines = io::BufReader::new(File::open(&args.filename)?)
+        .lines()
+        .collect::<Result<Vec<_>, _>>()?;
+    let (front_matter, lines) = split_front_matter(lines);
+    let mut math_macros = MathMacros::default();
+    if let Some(path) = &args.macros {
+        math_macros.load(path)?;
+    }
+    math_macros.add_front_matter(&front_matter, &base_dir)?;
+
+    let opts = Options {
+        code_backend: args.code_backend,
+        highlight_theme: args.highlight_theme,
+        base_dir,
+        list_depth: args.list_depth,
+        task_unchecked: args.task_unchecked,
+        task_checked: args.task_checked,
+        callout_titles: args.callout_title,
+        callout_colours: args.callout_colour,
+        theorem_counters: args.theorem_counters,
+        theorem_numbering: args.theorem_numbering,
+        footnote_mode: args.footnotes,
+        math_macros: Rc::new(math_macros),
+        thematic_break: args.thematic_break,
+        flavor: args.flavor,
+        ..Options::default()
+    };
+    if args.standalone {
+        // The preamble depends on what the document turns out to use
+        let body: String = convert(lines.into_iter().map(Ok), opts.clone()).collect();
+        print!("{}{}{}", preamble(&opts), body, postamble());
+    } else {
+        convert(lines.into_iter().map(Ok), opts)
+            .for_each(|processed_line| print!("{}", processed_line));
+    }
     Ok(())
 }
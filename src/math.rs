@@ -0,0 +1,156 @@
+use anyhow::{bail, Error};
+use lazy_static::lazy_static;
+use regex::Regex;
+
+lazy_static! {
+    static ref RE_LINE_LABEL: Regex =
+        Regex::new(r#"^(?<math>.*?)\s*<!--(?<label>[^>]+)-->\s*$"#).unwrap();
+}
+
+/// Display environments that number each line, and so take a label per line.
+const MULTI_LINE: [&str; 3] = ["align", "gather", "flalign"];
+/// Display environments of their own.
+const DISPLAY: [&str; 5] = ["equation", "align", "gather", "multline", "flalign"];
+/// Environments that go inside an equation.
+const INNER: [&str; 4] = ["cases", "aligned", "split", "gathered"];
+
+/// An open display math block, e.g. `$$align<!--eq:sys-->` up to the closing `$$`,
+/// or `\[` up to `\]`.
+/// The lines are held until the block closes, since a label on any line
+/// decides whether the environment is numbered.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MathBlock {
+    /// The display environment, without a star
+    env: &'static str,
+    /// An environment inside the equation, e.g. `cases`
+    inner: Option<&'static str>,
+    /// Always use the starred environment, e.g. for `$$align*`
+    starred: bool,
+    /// The label, which may also be given after the closing `$$`
+    pub label: Option<String>,
+    /// Opened with `\[`, so closed with `\]`
+    pub bracketed: bool,
+    lines: Vec<String>,
+}
+
+impl MathBlock {
+    /// Open a block in the named environment, `equation` if the name is empty.
+    /// Environments like `cases` that can't stand on their own are put in an equation.
+    pub fn open(name: &str, label: Option<String>) -> Result<MathBlock, Error> {
+        let starred = name.ends_with('*');
+        let name = name.trim_end_matches('*');
+        let (env, inner) = if name.is_empty() {
+            ("equation", None)
+        } else if let Some(env) = DISPLAY.iter().find(|env| **env == name) {
+            (*env, None)
+        } else if let Some(inner) = INNER.iter().find(|env| **env == name) {
+            ("equation", Some(*inner))
+        } else {
+            bail!(
+                "Unknown display math environment '{}'.  Expected one of {}, or {} inside an equation.",
+                name,
+                DISPLAY.join(", "),
+                INNER.join(", ")
+            );
+        };
+        Ok(MathBlock {
+            env,
+            inner,
+            starred,
+            label,
+            bracketed: false,
+            lines: Vec::new(),
+        })
+    }
+
+    /// Add a line of the block.
+    /// Blank lines are dropped, since LaTeX doesn't allow them in display math.
+    pub fn line(&mut self, line: &str) {
+        if !line.trim().is_empty() {
+            self.lines.push(line.to_owned());
+        }
+    }
+
+    /// The whole environment, once the block has closed.
+    /// It's numbered if the block or any of its lines has a label.  When only some
+    /// lines of a multi-line environment are labelled, the others aren't numbered.
+    pub fn end(&self) -> String {
+        let line_labels = self.lines.iter().any(|l| RE_LINE_LABEL.is_match(l));
+        let numbered = !self.starred && (self.label.is_some() || line_labels);
+        let only_labelled_lines =
+            numbered && self.label.is_none() && MULTI_LINE.contains(&self.env);
+        let env = if numbered {
+            self.env.to_owned()
+        } else {
+            format!("{}*", self.env)
+        };
+
+        let mut tex = format!("\\begin{{{}}}", env);
+        if let Some(label) = &self.label {
+            tex.push_str(&format!("\\label{{{}}}", label));
+        }
+        tex.push('\n');
+        if let Some(inner) = self.inner {
+            tex.push_str(&format!("\\begin{{{}}}\n", inner));
+        }
+        for line in &self.lines {
+            let (math, tag) = match RE_LINE_LABEL.captures(line) {
+                Some(cap) => (
+                    cap["math"].to_owned(),
+                    format!("\\label{{{}}}", cap["label"].trim()),
+                ),
+                None if only_labelled_lines => (line.to_owned(), "\\nonumber".to_owned()),
+                None => (line.to_owned(), String::new()),
+            };
+            // The label goes before the line break, if there is one
+            let math = math.trim_end();
+            match math.strip_suffix("\\\\") {
+                Some(math) if !tag.is_empty() => {
+                    tex.push_str(&format!("{} {} \\\\\n", math.trim_end(), tag))
+                }
+                _ if !tag.is_empty() => tex.push_str(&format!("{} {}\n", math, tag)),
+                _ => tex.push_str(&format!("{}\n", math)),
+            }
+        }
+        if let Some(inner) = self.inner {
+            tex.push_str(&format!("\\end{{{}}}\n", inner));
+        }
+        tex.push_str(&format!("\\end{{{}}}\n", env));
+        tex
+    }
+}
+
+#[cfg(test)]
+mod math_tests {
+    use super::*;
+
+    #[test]
+    fn test_math_environments() {
+        let mut block = MathBlock::open("align", None).unwrap();
+        block.line("a &= b \\\\ <!--eq:a-->");
+        block.line("");
+        block.line("c &= d");
+        assert_eq!(
+            block.end(),
+            "\\begin{align}\na &= b \\label{eq:a} \\\\\nc &= d \\nonumber\n\\end{align}\n"
+        );
+
+        let mut block = MathBlock::open("gather", None).unwrap();
+        block.line("x = 1");
+        assert_eq!(block.end(), "\\begin{gather*}\nx = 1\n\\end{gather*}\n");
+
+        let mut block = MathBlock::open("cases", Some("eq:abs".to_owned())).unwrap();
+        block.line("x & x > 0 \\\\");
+        block.line("-x & x \\le 0");
+        assert_eq!(
+            block.end(),
+            concat!(
+                "\\begin{equation}\\label{eq:abs}\n\\begin{cases}\n",
+                "x & x > 0 \\\\\n-x & x \\le 0\n",
+                "\\end{cases}\n\\end{equation}\n"
+            )
+        );
+
+        assert!(MathBlock::open("matrix", None).is_err());
+    }
+}
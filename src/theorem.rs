@@ -0,0 +1,117 @@
+use crate::converter::Options;
+use clap::ValueEnum;
+
+/// Whether the theorem-like environments count together or each on their own.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum TheoremCounters {
+    /// Theorem 1, Lemma 2, Definition 3, ...
+    #[default]
+    Shared,
+    /// Theorem 1, Lemma 1, Definition 1, ...
+    Separate,
+}
+
+/// What theorem numbers are reset by.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum TheoremNumbering {
+    /// Theorem 2.1 is the first theorem of chapter 2
+    #[default]
+    Chapter,
+    /// Theorem 2.3.1 is the first theorem of section 2.3
+    Section,
+    /// Theorems are numbered through the whole document
+    Document,
+}
+
+/// The built-in theorem-like environments: name, heading and amsthm style.
+/// Proofs are built into amsthm, so aren't declared.
+const THEOREMS: [(&str, &str, &str); 6] = [
+    ("theorem", "Theorem", "plain"),
+    ("lemma", "Lemma", "plain"),
+    ("corollary", "Corollary", "plain"),
+    ("definition", "Definition", "definition"),
+    ("example", "Example", "definition"),
+    ("remark", "Remark", "remark"),
+];
+
+/// The environment for a fenced div's class.
+/// Abbreviations of the built-in theorems are expanded, e.g. `thm` to `theorem`,
+/// and `unnumbered` theorems use the starred environments.
+/// Any other class is taken as the name of an environment.
+pub fn environment(class: &str, unnumbered: bool) -> String {
+    let name = match class {
+        "thm" => "theorem",
+        "lem" => "lemma",
+        "cor" => "corollary",
+        "def" | "defn" => "definition",
+        "ex" => "example",
+        "rem" => "remark",
+        _ => class,
+    };
+    if unnumbered && THEOREMS.iter().any(|(theorem, _, _)| *theorem == name) {
+        format!("{}*", name)
+    } else {
+        name.to_owned()
+    }
+}
+
+/// The `\newtheorem` declarations for the built-in environments, and their
+/// unnumbered variants.
+pub fn preamble(opts: &Options) -> String {
+    let within = match opts.theorem_numbering {
+        TheoremNumbering::Chapter => "[chapter]",
+        TheoremNumbering::Section => "[section]",
+        TheoremNumbering::Document => "",
+    };
+    let mut preamble = "\\usepackage{amsthm}\n".to_owned();
+    let mut style = "";
+    for (i, (name, heading, theorem_style)) in THEOREMS.iter().enumerate() {
+        if *theorem_style != style {
+            style = theorem_style;
+            preamble.push_str(&format!("\\theoremstyle{{{}}}\n", style));
+        }
+        if i > 0 && opts.theorem_counters == TheoremCounters::Shared {
+            // Share the first theorem's counter
+            preamble.push_str(&format!(
+                "\\newtheorem{{{}}}[{}]{{{}}}\n",
+                name, THEOREMS[0].0, heading
+            ));
+        } else {
+            preamble.push_str(&format!(
+                "\\newtheorem{{{}}}{{{}}}{}\n",
+                name, heading, within
+            ));
+        }
+        preamble.push_str(&format!("\\newtheorem*{{{}*}}{{{}}}\n", name, heading));
+    }
+    preamble
+}
+
+#[cfg(test)]
+mod theorem_tests {
+    use super::*;
+
+    #[test]
+    fn test_theorem_preamble() {
+        assert_eq!(environment("thm", false), "theorem");
+        assert_eq!(environment("lemma", true), "lemma*");
+        assert_eq!(environment("proof", true), "proof");
+        assert_eq!(environment("center", false), "center");
+
+        let preamble = preamble(&Options::default());
+        assert!(
+            preamble.contains("\\theoremstyle{plain}\n\\newtheorem{theorem}{Theorem}[chapter]\n")
+        );
+        assert!(preamble.contains("\\newtheorem{lemma}[theorem]{Lemma}\n"));
+        assert!(preamble.contains("\\theoremstyle{remark}\n"));
+
+        let opts = Options {
+            theorem_counters: TheoremCounters::Separate,
+            theorem_numbering: TheoremNumbering::Document,
+            ..Options::default()
+        };
+        let preamble = super::preamble(&opts);
+        assert!(preamble.contains("\\newtheorem{lemma}{Lemma}\n"));
+        assert!(preamble.contains("\\newtheorem*{definition*}{Definition}\n"));
+    }
+}